@@ -0,0 +1,216 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bb_rust::misc::{
+    compress, compress_async, compress_to_writer, compress_with_threshold, decompress,
+    decompress_async, decompress_from_reader, DEFAULT_COMPRESS_THRESHOLD,
+};
+use criterion::{criterion_group, BatchSize, BenchmarkId, Criterion, Throughput};
+use serde_json::{json, Value};
+
+/// Tracks the peak heap usage, to compare how much memory each variant needs.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Peak heap usage of `f` above what was allocated before it ran.
+fn peak_memory<R>(f: impl FnOnce() -> R) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    drop(f());
+    PEAK.load(Ordering::Relaxed) - before
+}
+
+/// An export-like payload with `rows` rows.
+fn payload(rows: usize) -> Value {
+    Value::Array(
+        (0..rows)
+            .map(|i| {
+                json!({
+                    "id": format!("86d09530-0a54-11ec-b69f-{:012}", i),
+                    "name": format!("Peripheral {}", i),
+                    "values": [i, i * 2, i * 3],
+                    "updatedAt": "2022-06-01T10:00:00.000Z",
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Typical small payloads: a GraphQL scalar, a GraphQL mutation response and DynamoDB items.
+fn small_payloads() -> Vec<(&'static str, Value)> {
+    vec![
+        ("scalar", json!("hejhej")),
+        (
+            "mutation",
+            json!({"data": {"updateAlarm": {"id": "86d09530-0a54-11ec-b69f-cf2fbd32de70"}}}),
+        ),
+        ("item", payload(1)),
+        ("items", payload(20)),
+    ]
+}
+
+fn report_sizes() {
+    for (name, input) in small_payloads() {
+        println!(
+            "{}: json {} B, compress {} B, compress_with_threshold {} B",
+            name,
+            serde_json::to_vec(&input).unwrap().len(),
+            compress(&input).unwrap().len(),
+            compress_with_threshold(&input, DEFAULT_COMPRESS_THRESHOLD)
+                .unwrap()
+                .len(),
+        );
+    }
+}
+
+/// The async variants run on a current thread runtime, as used by our lambdas.
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+}
+
+/// Run [compress_async] to completion, writing to a sink so only the memory needed for compressing is counted.
+fn compress_async_blocking(runtime: &tokio::runtime::Runtime, input: Value) {
+    runtime
+        .block_on(compress_async(input, &mut tokio::io::sink()))
+        .unwrap()
+}
+
+fn decompress_async_blocking(runtime: &tokio::runtime::Runtime, compressed: &[u8]) -> Value {
+    runtime
+        .block_on(decompress_async(&mut &compressed[..]))
+        .unwrap()
+}
+
+fn report_peak_memory() {
+    let runtime = runtime();
+    for rows in [1_000, 100_000] {
+        let input = payload(rows);
+        let compressed = compress(&input).unwrap();
+        println!(
+            "{} rows: compress {} B, compress_to_writer {} B, decompress {} B, decompress_from_reader {} B",
+            rows,
+            peak_memory(|| compress(&input).unwrap()),
+            peak_memory(|| compress_to_writer(&input, Vec::new()).unwrap()),
+            peak_memory(|| decompress::<Value>(&compressed).unwrap()),
+            peak_memory(|| decompress_from_reader::<Value, _>(compressed.as_slice()).unwrap()),
+        );
+        let owned = input.clone();
+        println!(
+            "{} rows: compress_async {} B, decompress_async {} B",
+            rows,
+            peak_memory(|| compress_async_blocking(&runtime, owned)),
+            peak_memory(|| decompress_async_blocking(&runtime, &compressed)),
+        );
+    }
+}
+
+fn bench_compression(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("compression");
+    for rows in [1_000, 100_000] {
+        let input = payload(rows);
+        let compressed = compress(&input).unwrap();
+        group.throughput(Throughput::Elements(rows as u64));
+        group.bench_with_input(BenchmarkId::new("compress", rows), &input, |b, input| {
+            b.iter(|| compress(input).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("compress_to_writer", rows),
+            &input,
+            |b, input| b.iter(|| compress_to_writer(input, Vec::new()).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("decompress", rows),
+            &compressed,
+            |b, compressed| b.iter(|| decompress::<Value>(compressed).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("decompress_from_reader", rows),
+            &compressed,
+            |b, compressed| {
+                b.iter(|| decompress_from_reader::<Value, _>(compressed.as_slice()).unwrap())
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("compress_async", rows),
+            &input,
+            |b, input| {
+                b.iter_batched(
+                    || input.clone(),
+                    |input| compress_async_blocking(&runtime, input),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("decompress_async", rows),
+            &compressed,
+            |b, compressed| b.iter(|| decompress_async_blocking(&runtime, compressed)),
+        );
+    }
+    group.finish();
+}
+
+fn bench_threshold(c: &mut Criterion) {
+    let mut group = c.benchmark_group("threshold");
+    for (name, input) in small_payloads() {
+        let compressed = compress(&input).unwrap();
+        let framed = compress_with_threshold(&input, DEFAULT_COMPRESS_THRESHOLD).unwrap();
+        group.bench_with_input(BenchmarkId::new("compress", name), &input, |b, input| {
+            b.iter(|| compress(input).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("compress_with_threshold", name),
+            &input,
+            |b, input| {
+                b.iter(|| compress_with_threshold(input, DEFAULT_COMPRESS_THRESHOLD).unwrap())
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("decompress", name),
+            &compressed,
+            |b, compressed| b.iter(|| decompress::<Value>(compressed).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("decompress_framed", name),
+            &framed,
+            |b, framed| b.iter(|| decompress::<Value>(framed).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_compression, bench_threshold
+}
+
+fn main() {
+    report_sizes();
+    report_peak_memory();
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
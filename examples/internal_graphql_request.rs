@@ -1,6 +1,7 @@
 use bb_rust::graphql::{
     batch_internal_graphql_request, internal_graphql_request, GraphQLRequestBody, GraphqlContext,
 };
+use bb_rust::types::Language;
 use serde_json::json;
 
 async fn single() -> anyhow::Result<()> {
@@ -15,8 +16,8 @@ async fn single() -> anyhow::Result<()> {
     let graphql = GraphQLRequestBody {
         query: query.to_string(),
         variables: json!(null),
-        context: GraphqlContext::new("eu-west-1_lu59lbvt7".to_string())
-            .set_default_language("en".to_string()),
+        context: GraphqlContext::new("eu-west-1_lu59lbvt7".parse()?)
+            .set_default_language(Language::english()),
     };
 
     let lambda = aws_sdk_lambda::Client::new(&aws_config::load_from_env().await);
@@ -39,8 +40,8 @@ async fn batch() -> anyhow::Result<()> {
     let request1 = GraphQLRequestBody {
         query: query1.to_string(),
         variables: json!(null),
-        context: GraphqlContext::new("eu-west-1_lu59lbvt7".to_string())
-            .set_default_language("en".to_string()),
+        context: GraphqlContext::new("eu-west-1_lu59lbvt7".parse()?)
+            .set_default_language(Language::english()),
     };
 
     let query2 = "query test {
@@ -52,8 +53,8 @@ async fn batch() -> anyhow::Result<()> {
     let request2 = GraphQLRequestBody {
         query: query2.to_string(),
         variables: json!(null),
-        context: GraphqlContext::new("eu-west-1_lu59lbvt7".to_string())
-            .set_default_language("en".to_string()),
+        context: GraphqlContext::new("eu-west-1_lu59lbvt7".parse()?)
+            .set_default_language(Language::english()),
     };
 
     let lambda = aws_sdk_lambda::Client::new(&aws_config::load_from_env().await);
@@ -0,0 +1,141 @@
+use serde::Serialize;
+
+use super::{GraphQLError, GraphQLRequestBody, GraphqlContext};
+
+/// Log target of the audit entries, so they can be routed or filtered separately from other logs.
+pub const AUDIT_LOG_TARGET: &str = "bb_rust::audit";
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct AuditEntry {
+    caller: String,
+    scopes: Vec<String>,
+    operation: Option<String>,
+    #[serde(rename = "functionName")]
+    function_name: String,
+    /// `ok`, or the [GraphQLError::code] of the failed call
+    outcome: &'static str,
+}
+
+/// The audit log entries of the privileged (i.e. service principal) requests of a call, collected before the
+/// requests are sent and logged with the outcome once it is known.
+pub(crate) struct PrivilegedCalls(Vec<AuditEntry>);
+
+impl PrivilegedCalls {
+    pub(crate) fn new<'a, V: 'a>(
+        requests: impl IntoIterator<Item = &'a GraphQLRequestBody<V>>,
+        function_name: &str,
+    ) -> Self {
+        PrivilegedCalls(
+            requests
+                .into_iter()
+                .filter_map(|request| audit_entry(&request.context, &request.query, function_name))
+                .collect(),
+        )
+    }
+
+    pub(crate) fn log<T>(self, result: &Result<T, GraphQLError>) {
+        let outcome = outcome(result);
+        for mut entry in self.0 {
+            entry.outcome = outcome;
+            match serde_json::to_string(&entry) {
+                Ok(entry) => log::info!(target: AUDIT_LOG_TARGET, "{}", entry),
+                Err(e) => {
+                    log::error!(target: AUDIT_LOG_TARGET, "Failed serializing audit entry: {}", e)
+                }
+            }
+        }
+    }
+}
+
+fn outcome<T>(result: &Result<T, GraphQLError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(e) => e.code(),
+    }
+}
+
+fn audit_entry(context: &GraphqlContext, query: &str, function_name: &str) -> Option<AuditEntry> {
+    let service = context.service_principal()?;
+    Some(AuditEntry {
+        caller: service.name().to_string(),
+        scopes: service.scopes().to_vec(),
+        operation: operation_name(query).map(str::to_string),
+        function_name: function_name.to_string(),
+        outcome: "pending",
+    })
+}
+
+/// The name of the first operation in the query, e.g. `test` for `query test { ... }`.
+fn operation_name(query: &str) -> Option<&str> {
+    let query = query.trim_start();
+    let rest = ["query", "mutation", "subscription"]
+        .iter()
+        .find_map(|operation_type| query.strip_prefix(operation_type))?;
+    if !rest.starts_with(|c: char| c.is_whitespace()) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let end = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    Some(&rest[..end]).filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{audit_entry, operation_name, outcome, AuditEntry, PrivilegedCalls};
+    use crate::graphql::{GraphQLError, GraphQLRequestBody, GraphqlContext};
+
+    #[test]
+    fn parse_operation_name() {
+        assert_eq!(
+            operation_name("query test { company { id } }"),
+            Some("test")
+        );
+        assert_eq!(
+            operation_name("\n  mutation update($id: ID!) {}"),
+            Some("update")
+        );
+        assert_eq!(operation_name("query { company { id } }"), None);
+        assert_eq!(operation_name("{ company { id } }"), None);
+        assert_eq!(operation_name("queryable { id }"), None);
+    }
+
+    #[test]
+    fn only_service_calls_are_audited() {
+        let user = GraphqlContext::new("eu-west-1_pool".parse().unwrap());
+        assert_eq!(audit_entry(&user, "query test {}", "function"), None);
+
+        let scopes = vec!["devices:read".to_string()];
+        let service = GraphqlContext::service("export-job".to_string(), scopes.clone());
+        assert_eq!(
+            audit_entry(&service, "query test {}", "function"),
+            Some(AuditEntry {
+                caller: "export-job".to_string(),
+                scopes,
+                operation: Some("test".to_string()),
+                function_name: "function".to_string(),
+                outcome: "pending",
+            })
+        );
+
+        let requests = [user, service].map(|context| GraphQLRequestBody {
+            query: "query test {}".to_string(),
+            variables: (),
+            context,
+        });
+        assert_eq!(PrivilegedCalls::new(&requests, "function").0.len(), 1);
+    }
+
+    #[test]
+    fn outcome_of_call() {
+        assert_eq!(outcome(&Ok::<_, GraphQLError>(())), "ok");
+        assert_eq!(
+            outcome::<()>(&Err(GraphQLError::LambdaFunctionBadStatusCode {
+                status_code: 403,
+                payload: String::new(),
+            })),
+            "DOWNSTREAM_FORBIDDEN"
+        );
+    }
+}
@@ -40,7 +40,7 @@ pub async fn gateway_graphql_request<V: Serialize, R: DeserializeOwned>(
         .await?;
 
     if let Some(err) = response.function_error {
-        return Err(GraphQLError::LambdaFunctionError(err));
+        return Err(GraphQLError::function_error(err, response.payload.as_ref()));
     }
     if response.status_code != 200 {
         return Err(GraphQLError::LambdaFunctionBadStatusCode {
@@ -0,0 +1,233 @@
+//! [async_graphql] guards that check access against the [GraphqlContext] stored in the schema/request data.
+//!
+//! ```ignore
+//! #[Object]
+//! impl Query {
+//!     #[graphql(guard = "LineAccess::new(&line_id)")]
+//!     async fn line(&self, line_id: String) -> Line {
+//!         ...
+//!     }
+//! }
+//! ```
+use async_graphql::{Context, ErrorExtensions, Guard, Result};
+
+use crate::types::peripheral_id::PeripheralId;
+
+use super::GraphqlContext;
+
+/// Extract the [GraphqlContext] from the [async_graphql::Context] data.
+pub trait GraphqlContextExt {
+    fn graphql_context(&self) -> Result<&GraphqlContext>;
+}
+
+impl GraphqlContextExt for Context<'_> {
+    fn graphql_context(&self) -> Result<&GraphqlContext> {
+        self.data::<GraphqlContext>()
+    }
+}
+
+fn forbidden(message: String) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, e| e.set("code", "FORBIDDEN"))
+}
+
+/// Allows the field if the [GraphqlContext] has access to the line.
+pub struct LineAccess<'a> {
+    line_id: &'a str,
+}
+
+impl<'a> LineAccess<'a> {
+    pub fn new<S: AsRef<str> + ?Sized>(line_id: &'a S) -> Self {
+        LineAccess {
+            line_id: line_id.as_ref(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Guard for LineAccess<'_> {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        if ctx.graphql_context()?.line_access_allowed(self.line_id) {
+            Ok(())
+        } else {
+            Err(forbidden(format!("No access to line `{}`", self.line_id)))
+        }
+    }
+}
+
+/// Allows the field if the [GraphqlContext] has access to the peripheral.
+pub struct PeripheralAccess<'a> {
+    peripheral_id: &'a PeripheralId,
+}
+
+impl<'a> PeripheralAccess<'a> {
+    pub fn new(peripheral_id: &'a PeripheralId) -> Self {
+        PeripheralAccess { peripheral_id }
+    }
+}
+
+#[async_trait::async_trait]
+impl Guard for PeripheralAccess<'_> {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        if ctx
+            .graphql_context()?
+            .peripheral_access_allowed(self.peripheral_id)
+        {
+            Ok(())
+        } else {
+            Err(forbidden(format!(
+                "No access to peripheral `{}`",
+                self.peripheral_id
+            )))
+        }
+    }
+}
+
+/// Allows the field if the [GraphqlContext] has access to the group.
+pub struct GroupAccess<'a> {
+    group_id: &'a str,
+}
+
+impl<'a> GroupAccess<'a> {
+    pub fn new<S: AsRef<str> + ?Sized>(group_id: &'a S) -> Self {
+        GroupAccess {
+            group_id: group_id.as_ref(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Guard for GroupAccess<'_> {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        if ctx.graphql_context()?.group_access_allowed(self.group_id) {
+            Ok(())
+        } else {
+            Err(forbidden(format!("No access to group `{}`", self.group_id)))
+        }
+    }
+}
+
+/// Allows the field if the [GraphqlContext] has access to the user pool.
+pub struct UserPoolAccess<'a> {
+    user_pool: &'a str,
+}
+
+impl<'a> UserPoolAccess<'a> {
+    pub fn new<S: AsRef<str> + ?Sized>(user_pool: &'a S) -> Self {
+        UserPoolAccess {
+            user_pool: user_pool.as_ref(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Guard for UserPoolAccess<'_> {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        if ctx
+            .graphql_context()?
+            .user_pool_access_allowed(self.user_pool)
+        {
+            Ok(())
+        } else {
+            Err(forbidden(format!(
+                "No access to user pool `{}`",
+                self.user_pool
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Request, Schema};
+
+    use super::{GroupAccess, LineAccess, PeripheralAccess, UserPoolAccess};
+    use crate::graphql::GraphqlContext;
+    use crate::types::ids::{GroupId, LineId, UserPoolId};
+    use crate::types::peripheral_id::PeripheralId;
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        #[graphql(guard = "LineAccess::new(&line_id)")]
+        async fn line(&self, line_id: LineId) -> Option<LineId> {
+            Some(line_id)
+        }
+
+        #[graphql(guard = "PeripheralAccess::new(&peripheral_id)")]
+        async fn peripheral(&self, peripheral_id: PeripheralId) -> Option<PeripheralId> {
+            Some(peripheral_id)
+        }
+
+        #[graphql(guard = "GroupAccess::new(&group_id)")]
+        async fn group(&self, group_id: GroupId) -> Option<GroupId> {
+            Some(group_id)
+        }
+
+        #[graphql(guard = "UserPoolAccess::new(&user_pool)")]
+        async fn user_pool(&self, user_pool: UserPoolId) -> Option<UserPoolId> {
+            Some(user_pool)
+        }
+    }
+
+    async fn execute(context: Option<GraphqlContext>, query: &str) -> async_graphql::Response {
+        let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+        let request = match context {
+            Some(context) => Request::new(query).data(context),
+            None => Request::new(query),
+        };
+        schema.execute(request).await
+    }
+
+    #[tokio::test]
+    async fn guards_allow_access() {
+        let context = GraphqlContext::new("eu-west-1_pool".parse().unwrap())
+            .allow_line_id("line".parse().unwrap())
+            .allow_peripheral_id("abc-1".parse().unwrap())
+            .allow_group_id("group".parse().unwrap());
+        let response = execute(
+            Some(context),
+            r#"{
+                line(lineId: "line")
+                peripheral(peripheralId: "abc-1")
+                group(groupId: "group")
+                userPool(userPool: "eu-west-1_pool")
+            }"#,
+        )
+        .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+    }
+
+    #[tokio::test]
+    async fn guards_deny_access() {
+        let queries = [
+            r#"{ line(lineId: "line") }"#,
+            r#"{ peripheral(peripheralId: "abc-1") }"#,
+            r#"{ group(groupId: "group") }"#,
+            r#"{ userPool(userPool: "eu-west-1_other") }"#,
+        ];
+        for query in queries {
+            let context = GraphqlContext::new("eu-west-1_pool".parse().unwrap());
+            let response = execute(Some(context), query).await;
+            assert_eq!(response.errors.len(), 1, "{query}");
+            assert_eq!(
+                response.errors[0].extensions.as_ref().unwrap().get("code"),
+                Some(&async_graphql::Value::from("FORBIDDEN"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn guards_require_graphql_context() {
+        let response = execute(None, r#"{ line(lineId: "line") }"#).await;
+        assert_eq!(response.errors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_ids_are_rejected() {
+        let context = GraphqlContext::new("eu-west-1_pool".parse().unwrap());
+        let response = execute(Some(context), r#"{ userPool(userPool: "pool") }"#).await;
+        assert_eq!(response.errors.len(), 1);
+        assert!(response.errors[0].message.contains("UserPoolId"));
+    }
+}
@@ -6,9 +6,17 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
-use crate::misc::{compress, decompress};
-use crate::types::peripheral_id::PeripheralId;
+use crate::misc::{
+    compress, decompress_limits, decompress_with_limits, DecompressLimits, RequestContext,
+};
+use crate::types::ids::{GroupId, LineId, UserPoolId, UserSub};
+use crate::types::peripheral_id::{PeripheralId, PeripheralIdLike, PeripheralSet};
+use crate::types::Language;
 
+use super::audit;
+use super::signing::{
+    SignatureError, SignedCall, SignedGraphqlContext, SigningKey, DEFAULT_SIGNATURE_TTL,
+};
 use super::GraphQLError;
 #[derive(Serialize)]
 pub struct GraphQLRequestBody<V> {
@@ -29,6 +37,11 @@ struct GraphQLRequestBodyToSend<V> {
 struct GraphqlContextWrapper {
     #[serde(rename = "graphqlContext")]
     pub graphql_context: GraphqlContext,
+    #[serde(
+        rename = "graphqlContextSignature",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub signature: Option<SignedGraphqlContext>,
 }
 
 #[serde_as]
@@ -38,6 +51,42 @@ struct PayloadToSend<T: Serialize> {
     body: T,
 }
 
+impl<V: Serialize> PayloadToSend<GraphQLRequestBodyToSend<V>> {
+    fn new(
+        graphql: GraphQLRequestBody<V>,
+        lambda_function_name: &str,
+        signing_key: Option<&SigningKey>,
+    ) -> Result<Self, SignatureError> {
+        let signature = match signing_key {
+            Some(key) => {
+                let variables = serde_json::to_value(&graphql.variables)?;
+                let call = SignedCall {
+                    function_name: lambda_function_name,
+                    query: &graphql.query,
+                    variables: &variables,
+                };
+                Some(SignedGraphqlContext::sign(
+                    &graphql.context,
+                    call,
+                    key,
+                    DEFAULT_SIGNATURE_TTL,
+                )?)
+            }
+            None => None,
+        };
+        Ok(PayloadToSend {
+            body: GraphQLRequestBodyToSend {
+                query: graphql.query,
+                variables: graphql.variables,
+                context: GraphqlContextWrapper {
+                    graphql_context: graphql.context,
+                    signature,
+                },
+            },
+        })
+    }
+}
+
 /// Invokes a graphql query against an *internal* AWS lambda, e.g. ms-graphql-devices.
 ///
 /// **Note**: Do not use this method for querying the public-facing ms-graphql-gateway.
@@ -46,40 +95,7 @@ pub async fn internal_graphql_request<V: Serialize, R: DeserializeOwned>(
     graphql: GraphQLRequestBody<V>,
     lambda_function_name: String,
 ) -> Result<graphql_client::Response<R>, GraphQLError> {
-    let graphql = GraphQLRequestBodyToSend {
-        query: graphql.query,
-        variables: graphql.variables,
-        context: GraphqlContextWrapper {
-            graphql_context: graphql.context,
-        },
-    };
-    let payload = PayloadToSend { body: graphql };
-    let payload = compress(payload)?;
-    let payload = format!("\"{}\"", base64::encode(payload));
-
-    let response = lambda
-        .invoke()
-        .function_name(lambda_function_name)
-        .invocation_type(InvocationType::RequestResponse)
-        .payload(Blob::new(payload))
-        .send()
-        .await?;
-    if let Some(err) = response.function_error {
-        return Err(GraphQLError::LambdaFunctionError(err));
-    }
-    if response.status_code != 200 {
-        return Err(GraphQLError::LambdaFunctionBadStatusCode {
-            payload: format!("{:?}", response.payload),
-            status_code: response.status_code,
-        });
-    }
-    let payload = response.payload.ok_or(GraphQLError::NoResponsePayload)?;
-
-    // The format of the payload received is "<base64>" (the quotation marks are included in the payload).
-    // We "parse" the string by removing the quotation marks, and then base64 decode it, before decompressing it.
-    let base64_decoded = base64::decode(&payload.as_ref()[1..payload.as_ref().len() - 1]).unwrap();
-    let [r]: [graphql_client::Response<R>; 1] = decompress(&base64_decoded)?;
-    Ok(r)
+    single_request(lambda, graphql, lambda_function_name, None).await
 }
 
 /// Invokes a batch of graphql queries against an *internal* AWS lambda, e.g. ms-graphql-devices.
@@ -90,32 +106,96 @@ pub async fn batch_internal_graphql_request<V: Serialize, R: DeserializeOwned>(
     graphql_requests: Vec<GraphQLRequestBody<V>>,
     lambda_function_name: String,
 ) -> Result<Vec<graphql_client::Response<R>>, GraphQLError> {
-    let payload = graphql_requests
-        .into_iter()
-        .map(|request| {
-            let graphql = GraphQLRequestBodyToSend {
-                query: request.query,
-                variables: request.variables,
-                context: GraphqlContextWrapper {
-                    graphql_context: request.context,
-                },
-            };
+    batch_request(lambda, graphql_requests, lambda_function_name, None).await
+}
 
-            PayloadToSend { body: graphql }
-        })
-        .collect::<Vec<_>>();
+/// Same as [internal_graphql_request], but the context is signed with `signing_key`
+/// so the receiving lambda can verify it was not forged.
+pub async fn signed_internal_graphql_request<V: Serialize, R: DeserializeOwned>(
+    lambda: &aws_sdk_lambda::client::Client,
+    graphql: GraphQLRequestBody<V>,
+    lambda_function_name: String,
+    signing_key: &SigningKey,
+) -> Result<graphql_client::Response<R>, GraphQLError> {
+    single_request(lambda, graphql, lambda_function_name, Some(signing_key)).await
+}
+
+/// Same as [batch_internal_graphql_request], but every context is signed with `signing_key`
+/// so the receiving lambda can verify it was not forged.
+pub async fn signed_batch_internal_graphql_request<V: Serialize, R: DeserializeOwned>(
+    lambda: &aws_sdk_lambda::client::Client,
+    graphql_requests: Vec<GraphQLRequestBody<V>>,
+    lambda_function_name: String,
+    signing_key: &SigningKey,
+) -> Result<Vec<graphql_client::Response<R>>, GraphQLError> {
+    batch_request(
+        lambda,
+        graphql_requests,
+        lambda_function_name,
+        Some(signing_key),
+    )
+    .await
+}
+
+/// Privileged calls are audited once their outcome is known, so failed calls are not logged as performed.
+async fn single_request<V: Serialize, R: DeserializeOwned>(
+    lambda: &aws_sdk_lambda::client::Client,
+    graphql: GraphQLRequestBody<V>,
+    lambda_function_name: String,
+    signing_key: Option<&SigningKey>,
+) -> Result<graphql_client::Response<R>, GraphQLError> {
+    let audit = audit::PrivilegedCalls::new([&graphql], &lambda_function_name);
+    let result = async {
+        let payload = PayloadToSend::new(graphql, &lambda_function_name, signing_key)?;
+        let [r]: [graphql_client::Response<R>; 1] =
+            invoke_internal(lambda, payload, lambda_function_name).await?;
+        Ok(r)
+    }
+    .await;
+    audit.log(&result);
+    result
+}
+
+async fn batch_request<V: Serialize, R: DeserializeOwned>(
+    lambda: &aws_sdk_lambda::client::Client,
+    graphql_requests: Vec<GraphQLRequestBody<V>>,
+    lambda_function_name: String,
+    signing_key: Option<&SigningKey>,
+) -> Result<Vec<graphql_client::Response<R>>, GraphQLError> {
+    let audit = audit::PrivilegedCalls::new(&graphql_requests, &lambda_function_name);
+    let result = async {
+        let payload = graphql_requests
+            .into_iter()
+            .map(|request| PayloadToSend::new(request, &lambda_function_name, signing_key))
+            .collect::<Result<Vec<_>, _>>()?;
+        invoke_internal(lambda, payload, lambda_function_name).await
+    }
+    .await;
+    audit.log(&result);
+    result
+}
+
+async fn invoke_internal<P: Serialize, R: DeserializeOwned>(
+    lambda: &aws_sdk_lambda::client::Client,
+    payload: P,
+    lambda_function_name: String,
+) -> Result<R, GraphQLError> {
     let payload = compress(payload)?;
     let payload = format!("\"{}\"", base64::encode(payload));
+    // Continue the trace of the request this call is made for in the invoked lambda
+    let client_context =
+        RequestContext::with_current(|context| context?.client_context()).map(base64::encode);
 
     let response = lambda
         .invoke()
         .function_name(lambda_function_name)
         .invocation_type(InvocationType::RequestResponse)
+        .set_client_context(client_context)
         .payload(Blob::new(payload))
         .send()
         .await?;
     if let Some(err) = response.function_error {
-        return Err(GraphQLError::LambdaFunctionError(err));
+        return Err(GraphQLError::function_error(err, response.payload.as_ref()));
     }
     if response.status_code != 200 {
         return Err(GraphQLError::LambdaFunctionBadStatusCode {
@@ -124,48 +204,203 @@ pub async fn batch_internal_graphql_request<V: Serialize, R: DeserializeOwned>(
         });
     }
     let payload = response.payload.ok_or(GraphQLError::NoResponsePayload)?;
+    decode_response(payload.as_ref(), &decompress_limits())
+}
 
-    // The format of the payload received is "<base64>" (the quotation marks are included in the payload).
-    // We "parse" the string by removing the quotation marks, and then base64 decode it, before decompressing it.
-    let base64_decoded = base64::decode(&payload.as_ref()[1..payload.as_ref().len() - 1]).unwrap();
-    let r: Vec<graphql_client::Response<R>> = decompress(&base64_decoded)?;
-    Ok(r)
+/// The format of the payload received is "<base64>" (the quotation marks are included in the payload).
+/// We "parse" the string by removing the quotation marks, and then base64 decode it, before decompressing it
+/// within `limits`, as the payload is not trusted.
+#[allow(clippy::result_large_err)]
+fn decode_response<R: DeserializeOwned>(
+    payload: &[u8],
+    limits: &DecompressLimits,
+) -> Result<R, GraphQLError> {
+    let base64 = payload
+        .strip_prefix(b"\"")
+        .and_then(|payload| payload.strip_suffix(b"\""))
+        .ok_or_else(|| {
+            GraphQLError::ResponseEncoding("payload is not a quoted string".to_string())
+        })?;
+    let compressed =
+        base64::decode(base64).map_err(|e| GraphQLError::ResponseEncoding(e.to_string()))?;
+    Ok(decompress_with_limits(&compressed, limits)?)
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 /// Based on https://github.com/BlackbirdHQ/module-graphql-service/blob/a096efdd573396a6cfa869bb9c44df968d941f4b/src/types.ts#L24
 pub struct GraphqlContext {
-    #[serde(rename = "defaultLanguage")]
-    default_language: String,
-    language: String,
-    #[serde(rename = "groupIds")]
-    group_ids: HashSet<String>,
-    #[serde(rename = "lineIds")]
-    line_ids: HashSet<String>,
+    /// Default language of the company, sent as an empty string when unknown
+    #[serde(rename = "defaultLanguage", with = "invalid_as_none")]
+    default_language: Option<Language>,
+    /// Language of the user, sent as an empty string when unknown
+    #[serde(with = "invalid_as_none")]
+    language: Option<Language>,
+    /// Invalid ids are skipped, as they cannot grant access to anything
+    #[serde(rename = "groupIds", deserialize_with = "skip_invalid::deserialize")]
+    group_ids: HashSet<GroupId>,
+    /// Invalid ids are skipped, as they cannot grant access to anything
+    #[serde(rename = "lineIds", deserialize_with = "skip_invalid::deserialize")]
+    line_ids: HashSet<LineId>,
     #[serde(rename = "peripheralIds")]
-    peripheral_ids: HashSet<PeripheralId>,
-    #[serde(rename = "userPools")]
-    user_pools: Vec<String>,
-    #[serde(rename = "userSub")]
-    user_sub: String,
-    #[serde(rename = "userPool")]
-    user_pool: String,
+    peripheral_ids: PeripheralSet,
+    /// Invalid ids are skipped, as they cannot grant access to anything
+    #[serde(rename = "userPools", deserialize_with = "skip_invalid::deserialize")]
+    user_pools: Vec<UserPoolId>,
+    /// Sent as an empty string when there is no user, e.g. for service contexts
+    #[serde(rename = "userSub", with = "empty_as_none")]
+    user_sub: Option<UserSub>,
+    /// Invalid ids are treated as not set, as they cannot grant access to anything
+    #[serde(rename = "userPool", with = "invalid_as_none")]
+    user_pool: Option<UserPoolId>,
     #[serde(rename = "requiredBy")]
     required_by: Option<Required>,
     #[serde(rename = "requires")]
     requires: Option<Required>,
+    #[serde(rename = "servicePrincipal", skip_serializing_if = "Option::is_none")]
+    service_principal: Option<ServicePrincipal>,
+}
+
+/// The system actor a [GraphqlContext] was created for, e.g. a background job, as opposed to a user.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServicePrincipal {
+    name: String,
+    scopes: Vec<String>,
+}
+
+impl ServicePrincipal {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+}
+
+/// (De)serialize an optional id as an empty string when it is not set, as other services expect a string.
+mod empty_as_none {
+    use std::str::FromStr;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &Option<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => value.serialize(serializer),
+            None => serializer.serialize_str(""),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+        D: Deserializer<'de>,
+    {
+        let string = String::deserialize(deserializer)?;
+        if string.is_empty() {
+            return Ok(None);
+        }
+        string.parse().map(Some).map_err(serde::de::Error::custom)
+    }
+}
+
+/// [empty_as_none], but values that cannot be parsed are logged and treated as not set, e.g. for a language, which
+/// only affects presentation and should not reject the whole context.
+mod invalid_as_none {
+    use std::str::FromStr;
+
+    use serde::{Deserialize, Deserializer};
+
+    pub use super::empty_as_none::serialize;
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+        D: Deserializer<'de>,
+    {
+        let string = String::deserialize(deserializer)?;
+        if string.is_empty() {
+            return Ok(None);
+        }
+        match string.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                log::warn!("Ignoring invalid value in context: {}", e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Deserialize a collection of ids, skipping (and logging) the entries that are not valid ids instead of rejecting
+/// the collection.
+mod skip_invalid {
+    use std::str::FromStr;
+
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, C, D>(deserializer: D) -> Result<C, D::Error>
+    where
+        C: IntoIterator + FromIterator<C::Item>,
+        C::Item: FromStr,
+        <C::Item as FromStr>::Err: std::fmt::Display,
+        D: Deserializer<'de>,
+    {
+        let strings = Vec::<String>::deserialize(deserializer)?;
+        Ok(strings
+            .into_iter()
+            .filter_map(|string| match string.parse() {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    log::warn!("Skipping invalid id {:?} in context: {}", string, e);
+                    None
+                }
+            })
+            .collect())
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct Required {
-    #[serde(rename = "lineIds")]
-    line_ids: HashSet<String>,
+    #[serde(rename = "lineIds", deserialize_with = "skip_invalid::deserialize")]
+    line_ids: HashSet<LineId>,
     #[serde(rename = "peripheralIds")]
-    peripheral_ids: HashSet<PeripheralId>,
+    peripheral_ids: PeripheralSet,
+    /// Only sent once group access has been narrowed, for services that do not know about it yet
+    #[serde(
+        rename = "groupIds",
+        default,
+        deserialize_with = "skip_invalid::deserialize",
+        skip_serializing_if = "HashSet::is_empty"
+    )]
+    group_ids: HashSet<GroupId>,
 }
 
 impl GraphqlContext {
-    pub fn new(user_pool: String) -> Self {
+    pub fn new(user_pool: UserPoolId) -> Self {
+        GraphqlContext {
+            default_language: Default::default(),
+            language: Default::default(),
+            group_ids: Default::default(),
+            line_ids: Default::default(),
+            peripheral_ids: Default::default(),
+            user_pools: Default::default(),
+            user_sub: Default::default(),
+            user_pool: Some(user_pool),
+            required_by: Default::default(),
+            requires: Default::default(),
+            service_principal: Default::default(),
+        }
+    }
+
+    /// Create a context for a service acting on its own behalf rather than on behalf of a user.
+    ///
+    /// The context is serialized with a `servicePrincipal` so receivers can tell it apart from a user context.
+    pub fn service(name: String, scopes: Vec<String>) -> Self {
         GraphqlContext {
             default_language: Default::default(),
             language: Default::default(),
@@ -174,23 +409,49 @@ impl GraphqlContext {
             peripheral_ids: Default::default(),
             user_pools: Default::default(),
             user_sub: Default::default(),
-            user_pool,
+            user_pool: Default::default(),
             required_by: Default::default(),
             requires: Default::default(),
+            service_principal: Some(ServicePrincipal { name, scopes }),
         }
     }
 
-    /// Get a reference to the graphql context's user pool.
-    pub fn user_pool(&self) -> &str {
+    pub fn service_principal(&self) -> Option<&ServicePrincipal> {
+        self.service_principal.as_ref()
+    }
+
+    pub fn is_service(&self) -> bool {
+        self.service_principal.is_some()
+    }
+
+    /// Set the graphql context's user pool.
+    pub fn set_user_pool(mut self, user_pool: UserPoolId) -> Self {
+        self.user_pool = Some(user_pool);
+        self
+    }
+
+    /// Get a reference to the graphql context's user pool, which service contexts may not have.
+    pub fn user_pool(&self) -> Option<&UserPoolId> {
         self.user_pool.as_ref()
     }
 
-    pub fn allow_line_id(mut self, line_id: String) -> Self {
+    /// Set the user the context acts on behalf of.
+    pub fn set_user_sub(mut self, user_sub: UserSub) -> Self {
+        self.user_sub = Some(user_sub);
+        self
+    }
+
+    /// The user the context acts on behalf of, if any.
+    pub fn user_sub(&self) -> Option<&UserSub> {
+        self.user_sub.as_ref()
+    }
+
+    pub fn allow_line_id(mut self, line_id: LineId) -> Self {
         self.line_ids.insert(line_id);
         self
     }
 
-    pub fn disallow_line_id(mut self, line_id: String) -> Self {
+    pub fn disallow_line_id(mut self, line_id: LineId) -> Self {
         self.line_ids.remove(&line_id);
         self
     }
@@ -209,24 +470,341 @@ impl GraphqlContext {
         self
     }
 
-    pub fn peripheral_access_allowed(&self, peripheral_id: &PeripheralId) -> bool {
+    /// The peripherals the context has access to, e.g. to find all allowed indexes of a device with
+    /// [PeripheralSet::by_uuid].
+    pub fn peripheral_ids(&self) -> &PeripheralSet {
+        &self.peripheral_ids
+    }
+
+    /// Takes either a [PeripheralId] or a [crate::types::peripheral_id::PeripheralIdRef], so ids parsed from a
+    /// request can be checked without allocating.
+    pub fn peripheral_access_allowed(&self, peripheral_id: &dyn PeripheralIdLike) -> bool {
         self.peripheral_ids.contains(peripheral_id)
     }
 
+    pub fn allow_group_id(mut self, group_id: GroupId) -> Self {
+        self.group_ids.insert(group_id);
+        self
+    }
+
+    pub fn disallow_group_id(mut self, group_id: GroupId) -> Self {
+        self.group_ids.remove(&group_id);
+        self
+    }
+
+    pub fn group_access_allowed(&self, group_id: &str) -> bool {
+        self.group_ids.contains(group_id)
+    }
+
+    /// Give access to another user pool than the one of the context.
+    pub fn allow_user_pool(mut self, user_pool: UserPoolId) -> Self {
+        if !self.user_pools.contains(&user_pool) {
+            self.user_pools.push(user_pool);
+        }
+        self
+    }
+
+    /// Whether the context is for the user pool, or has been given access to it.
+    pub fn user_pool_access_allowed(&self, user_pool: &str) -> bool {
+        self.user_pool
+            .iter()
+            .chain(&self.user_pools)
+            .any(|pool| pool.as_str() == user_pool)
+    }
+
+    /// Narrow the line access to the intersection of the current line ids and `line_ids`.
+    ///
+    /// Use this before forwarding the context to a downstream service that only needs a subset of the lines.
+    /// Narrowing never grants access to a line that was not already allowed.
+    pub fn narrow_to_line_ids<I: IntoIterator<Item = LineId>>(mut self, line_ids: I) -> Self {
+        self.record_required_by();
+        let requested: HashSet<LineId> = line_ids.into_iter().collect();
+        self.line_ids.retain(|line_id| requested.contains(line_id));
+        self.record_requires();
+        self
+    }
+
+    /// Narrow the peripheral access to the intersection of the current peripheral ids and `peripheral_ids`.
+    ///
+    /// Narrowing never grants access to a peripheral that was not already allowed.
+    pub fn narrow_to_peripheral_ids<I: IntoIterator<Item = PeripheralId>>(
+        mut self,
+        peripheral_ids: I,
+    ) -> Self {
+        self.record_required_by();
+        let requested: PeripheralSet = peripheral_ids.into_iter().collect();
+        self.peripheral_ids
+            .retain(|peripheral_id| requested.contains(peripheral_id));
+        self.record_requires();
+        self
+    }
+
+    /// Drop all group access, e.g. when the downstream call only concerns specific lines or peripherals.
+    ///
+    /// Like the other narrowing methods, the groups are dropped from `requires` as well.
+    pub fn without_group_access(mut self) -> Self {
+        self.record_required_by();
+        self.group_ids.clear();
+        self.record_requires();
+        self
+    }
+
+    /// Remember the access of the context before it was first narrowed, so downstream services can see
+    /// which access the narrowed context was derived from.
+    fn record_required_by(&mut self) {
+        if self.required_by.is_none() {
+            self.required_by = Some(Required {
+                line_ids: self.line_ids.clone(),
+                peripheral_ids: self.peripheral_ids.clone(),
+                group_ids: self.group_ids.clone(),
+            });
+        }
+    }
+
+    /// Record the access the narrowed context requires. An already present `requires` is intersected as well,
+    /// so it can never end up wider than what upstream required.
+    fn record_requires(&mut self) {
+        let requires = match self.requires.take() {
+            Some(Required {
+                line_ids,
+                peripheral_ids,
+                group_ids,
+            }) => Required {
+                line_ids: line_ids.intersection(&self.line_ids).cloned().collect(),
+                peripheral_ids: peripheral_ids
+                    .intersection(&self.peripheral_ids)
+                    .cloned()
+                    .collect(),
+                group_ids: group_ids.intersection(&self.group_ids).cloned().collect(),
+            },
+            None => Required {
+                line_ids: self.line_ids.clone(),
+                peripheral_ids: self.peripheral_ids.clone(),
+                group_ids: self.group_ids.clone(),
+            },
+        };
+        self.requires = Some(requires);
+    }
+
     // TODO extend with accessor methods as neccessary
 
     /// Set the graphql context's default language.
-    pub fn set_default_language(mut self, default_language: String) -> Self {
-        self.default_language = default_language;
+    pub fn set_default_language(mut self, default_language: Language) -> Self {
+        self.default_language = Some(default_language);
+        self
+    }
+
+    /// Set the language of the user.
+    pub fn set_language(mut self, language: Language) -> Self {
+        self.language = Some(language);
         self
     }
+
+    pub fn default_language(&self) -> Option<&Language> {
+        self.default_language.as_ref()
+    }
+
+    pub fn language(&self) -> Option<&Language> {
+        self.language.as_ref()
+    }
+
+    /// The language to respond in, see [Language::negotiate].
+    pub fn effective_language(&self, requested: Option<&Language>) -> Language {
+        Language::negotiate(
+            requested,
+            self.language.as_ref(),
+            self.default_language.as_ref(),
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
 
-    use super::GraphqlContext;
+    use proptest::prelude::*;
+
+    use super::{decode_response, GraphQLRequestBody, GraphqlContext, PayloadToSend};
+    use crate::graphql::{GraphQLError, InternalRequestContext, SignedCall, SigningKey};
+    use crate::misc::{compress, DecompressError, DecompressLimits};
+    use crate::types::ids::{GroupId, LineId};
+    use crate::types::peripheral_id::PeripheralId;
+    use crate::types::Language;
+
+    fn line_id() -> impl Strategy<Value = LineId> {
+        "[a-z]{1,3}".prop_map(|line_id| line_id.parse().unwrap())
+    }
+
+    fn group_id() -> impl Strategy<Value = GroupId> {
+        "[a-z]{1,3}".prop_map(|group_id| group_id.parse().unwrap())
+    }
+
+    fn peripheral_id() -> impl Strategy<Value = PeripheralId> {
+        ("[a-f0-9]{1,4}", "[0-9]{1,2}")
+            .prop_map(|(uuid, index)| PeripheralId::new(uuid, index).unwrap())
+    }
+
+    fn context() -> impl Strategy<Value = GraphqlContext> {
+        (
+            prop::collection::hash_set(line_id(), 0..8),
+            prop::collection::hash_set(peripheral_id(), 0..8),
+            prop::collection::hash_set(group_id(), 0..4),
+        )
+            .prop_map(|(line_ids, peripheral_ids, group_ids)| {
+                let mut context = GraphqlContext::new("eu-west-1_pool".parse().unwrap());
+                context.line_ids = line_ids;
+                context.peripheral_ids = peripheral_ids.into_iter().collect();
+                context.group_ids = group_ids;
+                context
+            })
+    }
+
+    #[test]
+    fn service_context_is_recognizable() {
+        let service = GraphqlContext::service("export-job".to_string(), vec!["read".to_string()])
+            .set_user_pool("eu-west-1_pool".parse().unwrap());
+        let json = serde_json::to_value(&service).unwrap();
+        assert_eq!(
+            json["servicePrincipal"],
+            serde_json::json!({"name": "export-job", "scopes": ["read"]})
+        );
+        let back: GraphqlContext = serde_json::from_value(json).unwrap();
+        assert_eq!(back.service_principal(), service.service_principal());
+        assert_eq!(back.user_pool().unwrap().as_str(), "eu-west-1_pool");
+
+        let user =
+            serde_json::to_value(GraphqlContext::new("eu-west-1_pool".parse().unwrap())).unwrap();
+        assert!(user.get("servicePrincipal").is_none());
+    }
+
+    #[test]
+    fn decode_response_payload() {
+        let limits = DecompressLimits::default();
+        let response = serde_json::json!([{"data": {"id": 1}}]);
+        let payload = format!("\"{}\"", base64::encode(compress(&response).unwrap()));
+        let decoded: serde_json::Value = decode_response(payload.as_bytes(), &limits).unwrap();
+        assert_eq!(decoded, response);
+
+        let unquoted = base64::encode(compress(&response).unwrap());
+        for payload in [response.to_string(), unquoted, "\"not base64\"".to_string()] {
+            assert!(
+                matches!(
+                    decode_response::<serde_json::Value>(payload.as_bytes(), &limits),
+                    Err(GraphQLError::ResponseEncoding(_))
+                ),
+                "{payload}"
+            );
+        }
+
+        let bomb = format!(
+            "\"{}\"",
+            base64::encode(compress(vec![0u8; 100_000]).unwrap())
+        );
+        assert!(matches!(
+            decode_response::<Vec<u8>>(bomb.as_bytes(), &limits.max_size(10_000)),
+            Err(GraphQLError::DecompressError(DecompressError::TooLarge(_)))
+        ));
+    }
+
+    #[test]
+    fn signed_payload_is_verifiable_by_receiver() {
+        let key = SigningKey::new("v1".to_string(), b"static test key".to_vec());
+        let request = GraphQLRequestBody {
+            query: "query test { company { id } }".to_string(),
+            variables: serde_json::json!({"first": 10, "after": "cursor"}),
+            context: GraphqlContext::new("eu-west-1_pool".parse().unwrap())
+                .allow_line_id("line".parse().unwrap()),
+        };
+        let payload =
+            serde_json::to_value(PayloadToSend::new(request, "function", Some(&key)).unwrap())
+                .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_str(payload["body"].as_str().unwrap()).unwrap();
+
+        let received: InternalRequestContext =
+            serde_json::from_value(body["context"].clone()).unwrap();
+        let call = SignedCall {
+            function_name: "function",
+            query: body["query"].as_str().unwrap(),
+            variables: &body["variables"],
+        };
+        let context = received.verify(call, &[key]).unwrap();
+        assert!(context.line_access_allowed("line"));
+    }
+
+    proptest! {
+        #[test]
+        fn narrowing_never_widens(
+            context in context(),
+            line_ids in prop::collection::vec(line_id(), 0..8),
+            peripheral_ids in prop::collection::vec(peripheral_id(), 0..8),
+            drop_groups in any::<bool>(),
+        ) {
+            let mut narrowed = context
+                .clone()
+                .narrow_to_line_ids(line_ids.clone())
+                .narrow_to_peripheral_ids(peripheral_ids.clone());
+            if drop_groups {
+                narrowed = narrowed.without_group_access();
+            }
+
+            prop_assert!(narrowed.line_ids.is_subset(&context.line_ids));
+            prop_assert!(narrowed.peripheral_ids.is_subset(&context.peripheral_ids));
+            prop_assert!(narrowed.group_ids.is_subset(&context.group_ids));
+            prop_assert_eq!(&narrowed.user_pools, &context.user_pools);
+
+            for line_id in &line_ids {
+                prop_assert_eq!(
+                    narrowed.line_access_allowed(line_id.as_str()),
+                    context.line_access_allowed(line_id.as_str())
+                );
+            }
+            for peripheral_id in &peripheral_ids {
+                prop_assert_eq!(
+                    narrowed.peripheral_access_allowed(peripheral_id),
+                    context.peripheral_access_allowed(peripheral_id)
+                );
+                prop_assert_eq!(
+                    narrowed.peripheral_access_allowed(&peripheral_id.as_id_ref()),
+                    narrowed.peripheral_access_allowed(peripheral_id)
+                );
+            }
+
+            let requires = narrowed.requires.as_ref().unwrap();
+            prop_assert_eq!(&requires.line_ids, &narrowed.line_ids);
+            prop_assert_eq!(&requires.peripheral_ids, &narrowed.peripheral_ids);
+            prop_assert_eq!(&requires.group_ids, &narrowed.group_ids);
+            if drop_groups {
+                prop_assert!(requires.group_ids.is_empty());
+            }
+            let required_by = narrowed.required_by.as_ref().unwrap();
+            prop_assert_eq!(&required_by.line_ids, &context.line_ids);
+            prop_assert_eq!(&required_by.peripheral_ids, &context.peripheral_ids);
+            prop_assert_eq!(&required_by.group_ids, &context.group_ids);
+        }
+
+        #[test]
+        fn narrowing_an_already_narrowed_context_never_widens(
+            context in context(),
+            first in prop::collection::vec(line_id(), 0..8),
+            second in prop::collection::vec(line_id(), 0..8),
+        ) {
+            let once = context.narrow_to_line_ids(first);
+            let twice = once.clone().narrow_to_line_ids(second);
+
+            prop_assert!(twice.line_ids.is_subset(&once.line_ids));
+            prop_assert!(twice
+                .requires
+                .as_ref()
+                .unwrap()
+                .line_ids
+                .is_subset(&once.requires.as_ref().unwrap().line_ids));
+            prop_assert_eq!(
+                &twice.required_by.as_ref().unwrap().line_ids,
+                &once.required_by.as_ref().unwrap().line_ids
+            );
+        }
+    }
 
     #[test]
     fn deserialize_graphql_context() {
@@ -243,7 +821,113 @@ mod tests {
         let c: GraphqlContext = serde_json::from_str(json).unwrap();
         assert_eq!(
             c.line_ids,
-            HashSet::from_iter(["1".to_string(), "2".to_string()])
-        )
+            HashSet::from_iter(["1".parse().unwrap(), "2".parse().unwrap()])
+        );
+        assert_eq!(c.user_pool(), None);
+        assert!(!c.user_pool_access_allowed("a"));
+        assert_eq!(c.user_sub().unwrap().as_str(), "asd");
+        assert_eq!(c.effective_language(None).as_str(), "de");
+        let requested = "fr-CA".parse().unwrap();
+        assert_eq!(c.effective_language(Some(&requested)), requested);
+    }
+
+    #[test]
+    fn dropping_group_access_drops_required_groups() {
+        let json = serde_json::json!({
+            "lineIds": ["1"],
+            "userPool": "eu-west-1_asd",
+            "defaultLanguage": "",
+            "language": "",
+            "groupIds": ["group"],
+            "peripheralIds": [],
+            "userPools": [],
+            "userSub": "",
+            "requires": {"lineIds": ["1"], "peripheralIds": [], "groupIds": ["group"]}
+        });
+        let c: GraphqlContext = serde_json::from_value(json).unwrap();
+        let narrowed = c.without_group_access();
+        assert!(!narrowed.group_access_allowed("group"));
+        let back = serde_json::to_value(&narrowed).unwrap();
+        assert_eq!(
+            back["requires"],
+            serde_json::json!({"lineIds": ["1"], "peripheralIds": []})
+        );
+        assert_eq!(back["requiredBy"]["groupIds"], serde_json::json!(["group"]));
+    }
+
+    #[test]
+    fn invalid_ids_are_skipped() {
+        let json = serde_json::json!({
+            "lineIds": ["1", "line 2", ""],
+            "userPool": "eu-west-1_asd",
+            "defaultLanguage": "en",
+            "language": "",
+            "groupIds": ["GROUP#1", "group"],
+            "peripheralIds": [],
+            "userPools": [],
+            "userSub": "",
+            "requires": {"lineIds": ["1", "LINE#2"], "peripheralIds": []}
+        });
+        let c: GraphqlContext = serde_json::from_value(json).unwrap();
+        assert_eq!(c.line_ids, HashSet::from_iter(["1".parse().unwrap()]));
+        assert_eq!(c.group_ids, HashSet::from_iter(["group".parse().unwrap()]));
+        assert_eq!(
+            c.requires.unwrap().line_ids,
+            HashSet::from_iter(["1".parse().unwrap()])
+        );
+    }
+
+    #[test]
+    fn invalid_user_pools_are_dropped() {
+        let json = serde_json::json!({
+            "lineIds": ["1"],
+            "userPool": "pool",
+            "defaultLanguage": "",
+            "language": "de",
+            "groupIds": [],
+            "peripheralIds": [],
+            "userPools": ["eu-central-1_a", "b", "eu-west-1_"],
+            "userSub": "asd"
+        });
+        let c: GraphqlContext = serde_json::from_value(json).unwrap();
+        assert_eq!(c.user_pool(), None);
+        assert!(!c.user_pool_access_allowed("pool"));
+        assert!(c.user_pool_access_allowed("eu-central-1_a"));
+        assert!(!c.user_pool_access_allowed("b"));
+        assert_eq!(c.line_ids, HashSet::from_iter(["1".parse().unwrap()]));
+        assert_eq!(c.user_sub().unwrap().as_str(), "asd");
+        assert_eq!(c.language().unwrap().as_str(), "de");
+    }
+
+    #[test]
+    fn empty_user_is_none() {
+        let json = serde_json::json!({
+            "lineIds": [],
+            "userPool": "",
+            "defaultLanguage": "",
+            "language": "",
+            "groupIds": [],
+            "peripheralIds": [],
+            "userPools": [],
+            "userSub": ""
+        });
+        let c: GraphqlContext = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(c.user_pool(), None);
+        assert_eq!(c.user_sub(), None);
+        assert_eq!(c.language(), None);
+        assert_eq!(c.effective_language(None), Language::english());
+        assert!(!c.user_pool_access_allowed(""));
+        let back = serde_json::to_value(&c).unwrap();
+        assert_eq!(back["userPool"], json["userPool"]);
+        assert_eq!(back["userSub"], json["userSub"]);
+        assert_eq!(back["language"], json["language"]);
+        assert_eq!(back["defaultLanguage"], json["defaultLanguage"]);
+
+        let mut invalid_language = json;
+        invalid_language["language"] = "english".into();
+        invalid_language["defaultLanguage"] = "de".into();
+        let c: GraphqlContext = serde_json::from_value(invalid_language).unwrap();
+        assert_eq!(c.language(), None);
+        assert_eq!(c.effective_language(None).as_str(), "de");
     }
 }
@@ -1,14 +1,28 @@
 use crate::misc::CompressError;
 use crate::misc::DecompressError;
 
+use async_graphql::ErrorExtensions;
+use aws_sdk_lambda::error::InvokeErrorKind;
+use aws_sdk_lambda::types::{Blob, SdkError};
 use thiserror::Error;
 
+mod audit;
 mod gateway;
+mod guards;
 mod internal;
+mod signing;
 
+pub use audit::AUDIT_LOG_TARGET;
 pub use gateway::{gateway_graphql_request, GatewayGraphQLRequestBody};
+pub use guards::{GraphqlContextExt, GroupAccess, LineAccess, PeripheralAccess, UserPoolAccess};
 pub use internal::{
-    batch_internal_graphql_request, internal_graphql_request, GraphQLRequestBody, GraphqlContext,
+    batch_internal_graphql_request, internal_graphql_request,
+    signed_batch_internal_graphql_request, signed_internal_graphql_request, GraphQLRequestBody,
+    GraphqlContext, ServicePrincipal,
+};
+pub use signing::{
+    InternalRequestContext, SignatureError, SignedCall, SignedGraphqlContext, SigningKey,
+    DEFAULT_SIGNATURE_TTL,
 };
 
 #[allow(clippy::large_enum_variant)]
@@ -22,12 +36,285 @@ pub enum GraphQLError {
     LambdaInvoke(#[from] aws_sdk_lambda::types::SdkError<aws_sdk_lambda::error::InvokeError>),
     #[error("lambda function error: {0}")]
     LambdaFunctionError(String),
+    #[error("lambda function timed out: {0}")]
+    LambdaFunctionTimeout(String),
     #[error("lambda function bad status code {status_code} with payload: {payload}")]
     LambdaFunctionBadStatusCode { status_code: i32, payload: String },
     #[error("no response payload")]
     NoResponsePayload,
+    #[error("bad response payload encoding: {0}")]
+    ResponseEncoding(String),
     #[error("bad json response. Error: {0}")]
     UnexpectedJsonResponse(serde_json::Error),
     #[error("bad format: {0}")]
     BadFormat(#[from] CompressError),
+    #[error("graphql context signature error: {0}")]
+    Signature(#[from] SignatureError),
+    #[error("downstream graphql errors: {}", downstream_message(.0))]
+    Downstream(Vec<graphql_client::Error>),
+    #[error("no data in graphql response")]
+    NoResponseData,
+}
+
+impl GraphQLError {
+    /// A stable, machine-readable code for the error, exposed as the `code` extension in graphql responses.
+    pub fn code(&self) -> &'static str {
+        match self {
+            GraphQLError::DecompressError(DecompressError::TooLarge(_)) => "PAYLOAD_TOO_LARGE",
+            GraphQLError::InvalidInputQuery(_)
+            | GraphQLError::DecompressError(_)
+            | GraphQLError::NoResponsePayload
+            | GraphQLError::ResponseEncoding(_)
+            | GraphQLError::UnexpectedJsonResponse(_)
+            | GraphQLError::BadFormat(_)
+            | GraphQLError::NoResponseData => "BAD_PAYLOAD",
+            GraphQLError::LambdaInvoke(SdkError::TimeoutError(_)) => "DOWNSTREAM_TIMEOUT",
+            GraphQLError::LambdaInvoke(SdkError::DispatchFailure(e)) if e.is_timeout() => {
+                "DOWNSTREAM_TIMEOUT"
+            }
+            GraphQLError::LambdaInvoke(SdkError::DispatchFailure(_)) => "DOWNSTREAM_UNAVAILABLE",
+            GraphQLError::LambdaInvoke(SdkError::ServiceError { err, .. }) => match &err.kind {
+                InvokeErrorKind::TooManyRequestsException(_)
+                | InvokeErrorKind::Ec2ThrottledException(_)
+                | InvokeErrorKind::EniLimitReachedException(_) => "DOWNSTREAM_THROTTLED",
+                InvokeErrorKind::ResourceNotReadyException(_)
+                | InvokeErrorKind::ResourceConflictException(_)
+                | InvokeErrorKind::ServiceException(_) => "DOWNSTREAM_UNAVAILABLE",
+                InvokeErrorKind::RequestTooLargeException(_)
+                | InvokeErrorKind::InvalidRequestContentException(_)
+                | InvokeErrorKind::UnsupportedMediaTypeException(_) => "BAD_PAYLOAD",
+                _ if err.code() == Some("AccessDeniedException") => "DOWNSTREAM_FORBIDDEN",
+                _ => "DOWNSTREAM_ERROR",
+            },
+            GraphQLError::LambdaInvoke(_) => "DOWNSTREAM_ERROR",
+            GraphQLError::LambdaFunctionError(_) => "DOWNSTREAM_ERROR",
+            GraphQLError::LambdaFunctionTimeout(_) => "DOWNSTREAM_TIMEOUT",
+            GraphQLError::LambdaFunctionBadStatusCode { status_code, .. } => match status_code {
+                401 | 403 => "DOWNSTREAM_FORBIDDEN",
+                429 => "DOWNSTREAM_THROTTLED",
+                500..=599 => "DOWNSTREAM_UNAVAILABLE",
+                _ => "DOWNSTREAM_ERROR",
+            },
+            GraphQLError::Signature(_) => "INVALID_SIGNATURE",
+            GraphQLError::Downstream(errors) => {
+                let forbidden = errors.iter().any(|error| {
+                    matches!(
+                        downstream_code(error),
+                        Some("FORBIDDEN" | "UNAUTHORIZED" | "UNAUTHENTICATED")
+                    )
+                });
+                if forbidden {
+                    "DOWNSTREAM_FORBIDDEN"
+                } else {
+                    "DOWNSTREAM_ERROR"
+                }
+            }
+        }
+    }
+
+    /// Whether the same request may succeed if retried later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.code(),
+            "DOWNSTREAM_TIMEOUT" | "DOWNSTREAM_THROTTLED" | "DOWNSTREAM_UNAVAILABLE"
+        )
+    }
+
+    fn function_error(function_error: String, payload: Option<&Blob>) -> Self {
+        // A lambda that runs out of time reports an unhandled error with a "Task timed out after ..." message
+        let payload = payload
+            .map(|payload| String::from_utf8_lossy(payload.as_ref()).into_owned())
+            .unwrap_or_default();
+        if payload.contains("Task timed out") {
+            GraphQLError::LambdaFunctionTimeout(payload)
+        } else {
+            GraphQLError::LambdaFunctionError(function_error)
+        }
+    }
+}
+
+fn downstream_message(errors: &[graphql_client::Error]) -> String {
+    errors
+        .iter()
+        .map(|e| e.message.as_str())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn downstream_code(error: &graphql_client::Error) -> Option<&str> {
+    error.extensions.as_ref()?.get("code")?.as_str()
+}
+
+/// Adds `code` and `retryable` extensions. Errors from a downstream graphql service are forwarded
+/// as `downstreamErrors`, each with its message and original extensions.
+impl ErrorExtensions for GraphQLError {
+    fn extend(&self) -> async_graphql::Error {
+        let message = match self {
+            GraphQLError::Downstream(errors) => downstream_message(errors),
+            _ => self.to_string(),
+        };
+        async_graphql::Error::new(message).extend_with(|_, extensions| {
+            if let GraphQLError::Downstream(errors) = self {
+                let downstream_errors = errors
+                    .iter()
+                    .map(|e| {
+                        serde_json::json!({
+                            "message": e.message,
+                            "extensions": e.extensions,
+                        })
+                    })
+                    .collect();
+                if let Ok(value) =
+                    async_graphql::Value::from_json(serde_json::Value::Array(downstream_errors))
+                {
+                    extensions.set("downstreamErrors", value);
+                }
+            }
+            extensions.set("code", self.code());
+            extensions.set("retryable", self.is_retryable());
+        })
+    }
+}
+
+/// Turn a [graphql_client::Response] into its data, or a [GraphQLError::Downstream] if it contains errors.
+#[allow(clippy::result_large_err)]
+pub trait ResponseExt<T> {
+    fn into_data(self) -> Result<T, GraphQLError>;
+}
+
+impl<T> ResponseExt<T> for graphql_client::Response<T> {
+    fn into_data(self) -> Result<T, GraphQLError> {
+        match (self.data, self.errors) {
+            (_, Some(errors)) if !errors.is_empty() => Err(GraphQLError::Downstream(errors)),
+            (Some(data), _) => Ok(data),
+            (None, _) => Err(GraphQLError::NoResponseData),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{ErrorExtensions, Value};
+    use serde_json::json;
+
+    use super::{GraphQLError, ResponseExt};
+    use crate::misc::{DecompressError, LimitExceeded};
+
+    fn extensions(error: &GraphQLError) -> async_graphql::ErrorExtensionValues {
+        error.extend().extensions.unwrap()
+    }
+
+    #[test]
+    fn error_codes() {
+        let error = GraphQLError::NoResponsePayload;
+        assert_eq!(
+            extensions(&error).get("code"),
+            Some(&Value::from("BAD_PAYLOAD"))
+        );
+        assert_eq!(
+            extensions(&error).get("retryable"),
+            Some(&Value::from(false))
+        );
+
+        let error = GraphQLError::LambdaFunctionBadStatusCode {
+            status_code: 429,
+            payload: String::new(),
+        };
+        assert_eq!(error.code(), "DOWNSTREAM_THROTTLED");
+        assert_eq!(
+            extensions(&error).get("retryable"),
+            Some(&Value::from(true))
+        );
+
+        let payload = aws_sdk_lambda::types::Blob::new(
+            r#"{"errorMessage":"2022-06-01T10:00:00.000Z abc Task timed out after 3.00 seconds"}"#,
+        );
+        let error = GraphQLError::function_error("Unhandled".to_string(), Some(&payload));
+        assert_eq!(error.code(), "DOWNSTREAM_TIMEOUT");
+        assert!(error.is_retryable());
+
+        let error = GraphQLError::function_error("Unhandled".to_string(), None);
+        assert_eq!(error.code(), "DOWNSTREAM_ERROR");
+
+        let error = GraphQLError::from(DecompressError::TooLarge(LimitExceeded::Size(1024)));
+        assert_eq!(error.code(), "PAYLOAD_TOO_LARGE");
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn downstream_errors_are_forwarded() {
+        let response: graphql_client::Response<serde_json::Value> = serde_json::from_value(json!({
+            "data": null,
+            "errors": [{
+                "message": "No access to line",
+                "extensions": {"code": "FORBIDDEN", "lineId": "1"}
+            }]
+        }))
+        .unwrap();
+        let error = response.into_data().unwrap_err();
+        assert_eq!(error.code(), "DOWNSTREAM_FORBIDDEN");
+
+        let extended = error.extend();
+        assert_eq!(extended.message, "No access to line");
+        let extensions = extended.extensions.unwrap();
+        assert_eq!(
+            extensions.get("code"),
+            Some(&Value::from("DOWNSTREAM_FORBIDDEN"))
+        );
+        assert_eq!(
+            extensions
+                .get("downstreamErrors")
+                .cloned()
+                .unwrap()
+                .into_json()
+                .unwrap(),
+            json!([{
+                "message": "No access to line",
+                "extensions": {"code": "FORBIDDEN", "lineId": "1"}
+            }])
+        );
+    }
+
+    #[test]
+    fn downstream_errors_keep_their_own_extensions() {
+        let response: graphql_client::Response<serde_json::Value> = serde_json::from_value(json!({
+            "data": null,
+            "errors": [
+                {"message": "Line not found", "extensions": {"code": "NOT_FOUND", "lineId": "1"}},
+                {"message": "No access to group", "extensions": {"code": "FORBIDDEN", "groupId": "2"}},
+                {"message": "Something failed"}
+            ]
+        }))
+        .unwrap();
+        let error = response.into_data().unwrap_err();
+        assert_eq!(error.code(), "DOWNSTREAM_FORBIDDEN");
+
+        let extended = error.extend();
+        assert_eq!(
+            extended.message,
+            "Line not found; No access to group; Something failed"
+        );
+        let extensions = extended.extensions.unwrap();
+        assert_eq!(
+            extensions
+                .get("downstreamErrors")
+                .cloned()
+                .unwrap()
+                .into_json()
+                .unwrap(),
+            json!([
+                {"message": "Line not found", "extensions": {"code": "NOT_FOUND", "lineId": "1"}},
+                {"message": "No access to group", "extensions": {"code": "FORBIDDEN", "groupId": "2"}},
+                {"message": "Something failed", "extensions": null}
+            ])
+        );
+        assert_eq!(extensions.get("lineId"), None);
+    }
+
+    #[test]
+    fn response_data() {
+        let response: graphql_client::Response<serde_json::Value> =
+            serde_json::from_value(json!({"data": {"id": 1}})).unwrap();
+        assert_eq!(response.into_data().unwrap(), json!({"id": 1}));
+    }
 }
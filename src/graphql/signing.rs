@@ -0,0 +1,488 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+
+use super::GraphqlContext;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long a signed context is valid for when signed by the internal graphql request functions.
+pub const DEFAULT_SIGNATURE_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[cfg(feature = "services_secretsmanager")]
+const CURRENT_STAGE: &str = "AWSCURRENT";
+#[cfg(feature = "services_secretsmanager")]
+const PREVIOUS_STAGE: &str = "AWSPREVIOUS";
+
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    #[error("graphql context is not signed")]
+    Missing,
+    #[error("graphql context is signed with unknown key `{0}`")]
+    UnknownKey(String),
+    #[error("graphql context signature does not match the context or call")]
+    Tampered,
+    #[error("graphql context signature expired at {0}")]
+    Expired(u64),
+    #[error("bad signature encoding: {0}")]
+    BadEncoding(#[from] base64::DecodeError),
+    #[error("bad json in signed context. Error: {0}")]
+    UnexpectedJson(#[from] serde_json::Error),
+    #[cfg(feature = "services_secretsmanager")]
+    #[error("failed fetching signing key: {0}")]
+    SecretsManager(
+        Box<
+            aws_sdk_secretsmanager::types::SdkError<
+                aws_sdk_secretsmanager::error::GetSecretValueError,
+            >,
+        >,
+    ),
+    #[error("signing key secret has no value")]
+    EmptySecret,
+}
+
+/// A shared secret used to sign and verify [GraphqlContext]s sent between services.
+#[derive(Clone)]
+pub struct SigningKey {
+    id: String,
+    secret: Vec<u8>,
+}
+
+impl SigningKey {
+    pub fn new(id: String, secret: Vec<u8>) -> Self {
+        SigningKey { id, secret }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Load the current version of a signing key from Secrets Manager, for senders to sign with.
+    #[cfg(feature = "services_secretsmanager")]
+    pub async fn from_secrets_manager(
+        secret_id: &str,
+        region: Option<&'static str>,
+    ) -> Result<Self, SignatureError> {
+        Self::from_secrets_manager_stage(secret_id, CURRENT_STAGE, region).await
+    }
+
+    /// Load the version of a signing key with `version_stage`, e.g. `AWSPREVIOUS`, from Secrets Manager.
+    /// The secret version id is used as key id.
+    #[cfg(feature = "services_secretsmanager")]
+    pub async fn from_secrets_manager_stage(
+        secret_id: &str,
+        version_stage: &str,
+        region: Option<&'static str>,
+    ) -> Result<Self, SignatureError> {
+        let secret = crate::services::secretsmanager::secrets_manager(region)
+            .await
+            .get_secret_value()
+            .secret_id(secret_id)
+            .version_stage(version_stage)
+            .send()
+            .await
+            .map_err(|e| SignatureError::SecretsManager(Box::new(e)))?;
+        Self::from_secret(secret_id, &secret)
+    }
+
+    /// Load the current and, if there is one, the previous version of a signing key from Secrets Manager, for
+    /// receivers to verify with, so they keep accepting contexts signed with either version during a rotation.
+    #[cfg(feature = "services_secretsmanager")]
+    pub async fn verification_keys_from_secrets_manager(
+        secret_id: &str,
+        region: Option<&'static str>,
+    ) -> Result<Vec<Self>, SignatureError> {
+        let current = Self::from_secrets_manager(secret_id, region).await?;
+        match Self::from_secrets_manager_stage(secret_id, PREVIOUS_STAGE, region).await {
+            Ok(previous) => Ok(vec![current, previous]),
+            // A secret that was never rotated has no previous version
+            Err(SignatureError::SecretsManager(e))
+                if matches!(
+                    e.as_ref(),
+                    aws_sdk_secretsmanager::types::SdkError::ServiceError { err, .. }
+                        if err.is_resource_not_found_exception()
+                ) =>
+            {
+                Ok(vec![current])
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(feature = "services_secretsmanager")]
+    fn from_secret(
+        secret_id: &str,
+        secret: &aws_sdk_secretsmanager::output::GetSecretValueOutput,
+    ) -> Result<Self, SignatureError> {
+        let id = secret.version_id().unwrap_or(secret_id).to_string();
+        match (secret.secret_binary(), secret.secret_string()) {
+            (Some(binary), _) => Ok(SigningKey::new(id, binary.as_ref().to_vec())),
+            (None, Some(string)) => Ok(SigningKey::new(id, string.as_bytes().to_vec())),
+            (None, None) => Err(SignatureError::EmptySecret),
+        }
+    }
+
+    fn mac(&self, expires_at: u64, call: &SignedCall, payload: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC can take a key of any size");
+        // Every part is length prefixed, so no part can be shifted into another
+        let variables = call.variables.to_string();
+        for part in [
+            self.id.as_str(),
+            &expires_at.to_string(),
+            function_name_of(call.function_name),
+            call.query,
+            &variables,
+            payload,
+        ] {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part.as_bytes());
+        }
+        mac
+    }
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The call a [SignedGraphqlContext] is bound to, so a captured context cannot be replayed with a different
+/// query or against a different lambda.
+#[derive(Debug, Clone, Copy)]
+pub struct SignedCall<'a> {
+    /// The invoked lambda, by name or ARN. A receiver passes its own `AWS_LAMBDA_FUNCTION_NAME`.
+    pub function_name: &'a str,
+    pub query: &'a str,
+    pub variables: &'a serde_json::Value,
+}
+
+/// The plain name of a lambda given by name, partial or full ARN, without version or alias.
+fn function_name_of(function: &str) -> &str {
+    let name = function
+        .split_once(":function:")
+        .map_or(function, |(_, name)| name);
+    name.split(':').next().unwrap_or(name)
+}
+
+/// A [GraphqlContext] together with a HMAC-SHA256 signature over its serialized form and the [SignedCall].
+///
+/// The context is kept as the exact serialized string that was signed, since re-serializing a deserialized
+/// context does not give the same bytes (e.g. the order of the id sets).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SignedGraphqlContext {
+    #[serde(rename = "keyId")]
+    key_id: String,
+    #[serde(rename = "expiresAt")]
+    expires_at: u64,
+    payload: String,
+    signature: String,
+}
+
+impl SignedGraphqlContext {
+    pub fn sign(
+        context: &GraphqlContext,
+        call: SignedCall,
+        key: &SigningKey,
+        ttl: Duration,
+    ) -> Result<Self, SignatureError> {
+        Self::sign_at(context, call, key, ttl, SystemTime::now())
+    }
+
+    fn sign_at(
+        context: &GraphqlContext,
+        call: SignedCall,
+        key: &SigningKey,
+        ttl: Duration,
+        now: SystemTime,
+    ) -> Result<Self, SignatureError> {
+        let expires_at = unix_seconds(now + ttl);
+        let payload = serde_json::to_string(context)?;
+        let signature = key.mac(expires_at, &call, &payload).finalize().into_bytes();
+        Ok(SignedGraphqlContext {
+            key_id: key.id.clone(),
+            expires_at,
+            payload,
+            signature: base64::encode(signature),
+        })
+    }
+
+    /// Verify the signature for the received `call` with whichever of `keys` it was signed with, and return the
+    /// signed context.
+    pub fn verify(
+        &self,
+        call: SignedCall,
+        keys: &[SigningKey],
+    ) -> Result<GraphqlContext, SignatureError> {
+        self.verify_at(call, keys, SystemTime::now())
+    }
+
+    fn verify_at(
+        &self,
+        call: SignedCall,
+        keys: &[SigningKey],
+        now: SystemTime,
+    ) -> Result<GraphqlContext, SignatureError> {
+        let key = keys
+            .iter()
+            .find(|key| key.id == self.key_id)
+            .ok_or_else(|| SignatureError::UnknownKey(self.key_id.clone()))?;
+        let signature = base64::decode(&self.signature)?;
+        key.mac(self.expires_at, &call, &self.payload)
+            .verify_slice(&signature)
+            .map_err(|_| SignatureError::Tampered)?;
+        if unix_seconds(now) > self.expires_at {
+            return Err(SignatureError::Expired(self.expires_at));
+        }
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+}
+
+/// The `context` of an internal graphql request, as received by the invoked lambda.
+#[derive(Deserialize, Debug)]
+pub struct InternalRequestContext {
+    #[serde(rename = "graphqlContext")]
+    graphql_context: GraphqlContext,
+    #[serde(rename = "graphqlContextSignature", default)]
+    signature: Option<SignedGraphqlContext>,
+}
+
+impl InternalRequestContext {
+    /// Return the signed context. Fails if the context is unsigned, tampered with, expired or was signed for a
+    /// different call.
+    pub fn verify(
+        self,
+        call: SignedCall,
+        keys: &[SigningKey],
+    ) -> Result<GraphqlContext, SignatureError> {
+        self.signature
+            .ok_or(SignatureError::Missing)?
+            .verify(call, keys)
+    }
+
+    /// Return the context without checking any signature, for services that have not yet enforced signing.
+    pub fn into_unverified(self) -> GraphqlContext {
+        self.graphql_context
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use serde_json::json;
+
+    use super::{
+        function_name_of, InternalRequestContext, SignatureError, SignedCall, SignedGraphqlContext,
+        SigningKey,
+    };
+    use crate::graphql::GraphqlContext;
+
+    const QUERY: &str = "query lines { lines { id } }";
+
+    fn key() -> SigningKey {
+        SigningKey::new("v1".to_string(), b"static test key".to_vec())
+    }
+
+    fn context() -> GraphqlContext {
+        GraphqlContext::new("eu-west-1_pool".parse().unwrap())
+            .allow_line_id("line".parse().unwrap())
+    }
+
+    fn call(variables: &serde_json::Value) -> SignedCall<'_> {
+        SignedCall {
+            function_name: "arn:aws:lambda:eu-west-1:123456789012:function:ms-graphql-devices",
+            query: QUERY,
+            variables,
+        }
+    }
+
+    fn sign() -> SignedGraphqlContext {
+        let variables = json!({"first": 10});
+        SignedGraphqlContext::sign(
+            &context(),
+            call(&variables),
+            &key(),
+            Duration::from_secs(60),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let signed = sign();
+        let other_key = SigningKey::new("v0".to_string(), b"old key".to_vec());
+        let variables = json!({"first": 10});
+        let received = SignedCall {
+            // As seen by the receiver in `AWS_LAMBDA_FUNCTION_NAME`
+            function_name: "ms-graphql-devices",
+            ..call(&variables)
+        };
+        let verified = signed.verify(received, &[other_key, key()]).unwrap();
+        assert!(verified.line_access_allowed("line"));
+    }
+
+    #[cfg(feature = "services_secretsmanager")]
+    #[test]
+    fn verify_with_previous_key_during_rotation() {
+        use aws_sdk_secretsmanager::output::GetSecretValueOutput;
+
+        let secret = |version_id: &str, value: &str| {
+            let output = GetSecretValueOutput::builder()
+                .version_id(version_id)
+                .secret_string(value)
+                .build();
+            SigningKey::from_secret("signing-key", &output).unwrap()
+        };
+        let previous = secret("8d0c6d2e", "previous secret");
+        let current = secret("1f3a9b7c", "current secret");
+        assert_eq!(previous.id(), "8d0c6d2e");
+
+        let variables = json!({"first": 10});
+        let signed = SignedGraphqlContext::sign(
+            &context(),
+            call(&variables),
+            &previous,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert!(signed
+            .verify(call(&variables), &[current.clone(), previous])
+            .is_ok());
+        assert!(matches!(
+            signed.verify(call(&variables), &[current]),
+            Err(SignatureError::UnknownKey(_))
+        ));
+        assert!(matches!(
+            SigningKey::from_secret("signing-key", &GetSecretValueOutput::builder().build()),
+            Err(SignatureError::EmptySecret)
+        ));
+    }
+
+    #[test]
+    fn function_names() {
+        for function in [
+            "ms-graphql-devices",
+            "ms-graphql-devices:live",
+            "123456789012:function:ms-graphql-devices",
+            "arn:aws:lambda:eu-west-1:123456789012:function:ms-graphql-devices:3",
+        ] {
+            assert_eq!(
+                function_name_of(function),
+                "ms-graphql-devices",
+                "{function}"
+            );
+        }
+    }
+
+    #[test]
+    fn reject_tampered_context() {
+        let variables = json!({"first": 10});
+        let mut signed = sign();
+        signed.payload = signed.payload.replace(r#""line""#, r#""other""#);
+        assert!(matches!(
+            signed.verify(call(&variables), &[key()]),
+            Err(SignatureError::Tampered)
+        ));
+
+        let mut signed = sign();
+        signed.expires_at += 3600;
+        assert!(matches!(
+            signed.verify(call(&variables), &[key()]),
+            Err(SignatureError::Tampered)
+        ));
+
+        let forged_key = SigningKey::new("v1".to_string(), b"guessed key".to_vec());
+        assert!(matches!(
+            sign().verify(call(&variables), &[forged_key]),
+            Err(SignatureError::Tampered)
+        ));
+    }
+
+    #[test]
+    fn reject_replay_for_other_call() {
+        let signed = sign();
+        let variables = json!({"first": 10});
+        let other_variables = json!({"first": 10000});
+        for replayed in [
+            SignedCall {
+                query: "mutation { deleteLine(id: \"line\") }",
+                ..call(&variables)
+            },
+            call(&other_variables),
+            SignedCall {
+                function_name: "ms-graphql-users",
+                ..call(&variables)
+            },
+        ] {
+            assert!(
+                matches!(
+                    signed.verify(replayed, &[key()]),
+                    Err(SignatureError::Tampered)
+                ),
+                "{replayed:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn reject_expired_context() {
+        let now = SystemTime::now();
+        let variables = json!(null);
+        let signed = SignedGraphqlContext::sign_at(
+            &context(),
+            call(&variables),
+            &key(),
+            Duration::from_secs(60),
+            now,
+        )
+        .unwrap();
+        assert!(signed
+            .verify_at(call(&variables), &[key()], now + Duration::from_secs(30))
+            .is_ok());
+        assert!(matches!(
+            signed.verify_at(call(&variables), &[key()], now + Duration::from_secs(120)),
+            Err(SignatureError::Expired(_))
+        ));
+    }
+
+    #[test]
+    fn reject_unknown_key_and_missing_signature() {
+        let variables = json!({"first": 10});
+        let other_key = SigningKey::new("v2".to_string(), b"static test key".to_vec());
+        assert!(matches!(
+            sign().verify(call(&variables), &[other_key]),
+            Err(SignatureError::UnknownKey(_))
+        ));
+
+        let json = r#"{
+            "graphqlContext": {
+                "lineIds": ["line"],
+                "userPool": "eu-west-1_pool",
+                "defaultLanguage": "",
+                "language": "",
+                "groupIds": [],
+                "peripheralIds": [],
+                "userPools": [],
+                "userSub": ""
+            }
+        }"#;
+        let received: InternalRequestContext = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            received.verify(call(&variables), &[key()]),
+            Err(SignatureError::Missing)
+        ));
+    }
+}
@@ -0,0 +1,371 @@
+use std::{
+    fmt,
+    io::{Read, Write},
+    marker::PhantomData,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{
+    decompress_limits, frame, framed_decoder,
+    limits::{LimitError, LimitExceeded},
+    DecompressLimits, GzippedJSON, DEFAULT_COMPRESS_THRESHOLD,
+};
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("codec error: {0}")]
+    Codec(#[from] std::io::Error),
+    #[error("format error: {0}")]
+    Format(Box<dyn std::error::Error + Send + Sync>),
+    #[error("decoded data is too large: {0}")]
+    TooLarge(#[from] LimitExceeded),
+}
+
+impl From<LimitError> for CodecError {
+    fn from(e: LimitError) -> Self {
+        match e {
+            LimitError::Io(e) => CodecError::Codec(e),
+            LimitError::Exceeded(exceeded) => CodecError::TooLarge(exceeded),
+        }
+    }
+}
+
+/// A compression algorithm for [Compressed].
+pub trait Codec {
+    fn encode(data: &[u8]) -> std::io::Result<Vec<u8>>;
+    /// A reader of the decoded data, which is read subject to the [super::DecompressLimits].
+    fn decoder<'a>(data: &'a [u8]) -> std::io::Result<Box<dyn Read + 'a>>;
+}
+
+/// A serialization format for [Compressed].
+pub trait Format {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::Error>;
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::Error>;
+
+    /// Check the encoded data against the [DecompressLimits] that depend on the format, e.g. the nesting depth.
+    fn check_limits(_bytes: &[u8], _limits: &DecompressLimits) -> Result<(), LimitExceeded> {
+        Ok(())
+    }
+}
+
+/// Gzip, which like [GzippedJSON] also decodes data framed by [super::compress_with_threshold].
+pub struct Gzip;
+
+impl Codec for Gzip {
+    fn encode(data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut e = GzEncoder::new(Vec::new(), Compression::default());
+        e.write_all(data)?;
+        e.finish()
+    }
+
+    fn decoder<'a>(data: &'a [u8]) -> std::io::Result<Box<dyn Read + 'a>> {
+        framed_decoder(data)
+    }
+}
+
+/// Gzip for data of at least `THRESHOLD` bytes, with the framing of [super::compress_with_threshold].
+///
+/// Also decodes plain [Gzip], so it can replace it for data that is already stored.
+pub struct AdaptiveGzip<const THRESHOLD: usize = DEFAULT_COMPRESS_THRESHOLD>;
+
+impl<const THRESHOLD: usize> Codec for AdaptiveGzip<THRESHOLD> {
+    fn encode(data: &[u8]) -> std::io::Result<Vec<u8>> {
+        frame(data, THRESHOLD)
+    }
+
+    fn decoder<'a>(data: &'a [u8]) -> std::io::Result<Box<dyn Read + 'a>> {
+        framed_decoder(data)
+    }
+}
+
+/// No compression, e.g. for payloads that are too small to gain from it.
+pub struct Identity;
+
+impl Codec for Identity {
+    fn encode(data: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    fn decoder<'a>(data: &'a [u8]) -> std::io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(data))
+    }
+}
+
+#[cfg(feature = "codec_zstd")]
+pub struct Zstd;
+
+#[cfg(feature = "codec_zstd")]
+impl Codec for Zstd {
+    fn encode(data: &[u8]) -> std::io::Result<Vec<u8>> {
+        zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    fn decoder<'a>(data: &'a [u8]) -> std::io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(zstd::Decoder::new(data)?))
+    }
+}
+
+#[cfg(feature = "codec_brotli")]
+pub struct Brotli;
+
+#[cfg(feature = "codec_brotli")]
+impl Codec for Brotli {
+    fn encode(mut data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        brotli::BrotliCompress(&mut data, &mut output, &Default::default())?;
+        Ok(output)
+    }
+
+    fn decoder<'a>(data: &'a [u8]) -> std::io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(brotli::Decompressor::new(data, 4096)))
+    }
+}
+
+pub struct Json;
+
+impl Format for Json {
+    type Error = serde_json::Error;
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::Error> {
+        serde_json::to_vec(value)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::Error> {
+        serde_json::from_slice(bytes)
+    }
+
+    fn check_limits(bytes: &[u8], limits: &DecompressLimits) -> Result<(), LimitExceeded> {
+        limits.check_json_depth(bytes)
+    }
+}
+
+/// MessagePack, with struct fields serialized by name so that added and optional fields keep working.
+#[cfg(feature = "format_msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "format_msgpack")]
+impl Format for MessagePack {
+    type Error = MessagePackError;
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::Error> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::Error> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+#[cfg(feature = "format_msgpack")]
+#[derive(Error, Debug)]
+pub enum MessagePackError {
+    #[error("MessagePack encode error: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error("MessagePack decode error: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+}
+
+#[cfg(feature = "format_cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "format_cbor")]
+impl Format for Cbor {
+    type Error = CborError;
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::Error> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::Error> {
+        Ok(ciborium::de::from_reader(bytes)?)
+    }
+}
+
+#[cfg(feature = "format_cbor")]
+#[derive(Error, Debug)]
+pub enum CborError {
+    #[error("CBOR encode error: {0}")]
+    Encode(#[from] ciborium::ser::Error<std::io::Error>),
+    #[error("CBOR decode error: {0}")]
+    Decode(#[from] ciborium::de::Error<std::io::Error>),
+}
+
+/// Serialized as `codec(format(data))` bytes, e.g. a DynamoDB binary attribute.
+///
+/// `Compressed<T>` (gzip and JSON) has the same representation as [GzippedJSON].
+pub struct Compressed<T, C = Gzip, F = Json> {
+    pub data: T,
+    encoding: PhantomData<fn() -> (C, F)>,
+}
+
+impl<T, C, F> Compressed<T, C, F> {
+    pub fn new(data: T) -> Self {
+        Compressed {
+            data,
+            encoding: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+}
+
+impl<T: Serialize, C: Codec, F: Format> Compressed<T, C, F> {
+    pub fn encode(&self) -> Result<Vec<u8>, CodecError> {
+        let bytes = F::to_vec(&self.data).map_err(|e| CodecError::Format(Box::new(e)))?;
+        Ok(C::encode(&bytes)?)
+    }
+}
+
+impl<T: DeserializeOwned, C: Codec, F: Format> Compressed<T, C, F> {
+    pub fn decode(bytes: &[u8]) -> Result<Self, CodecError> {
+        Self::decode_with_limits(bytes, &decompress_limits())
+    }
+
+    pub fn decode_with_limits(bytes: &[u8], limits: &DecompressLimits) -> Result<Self, CodecError> {
+        let bytes = limits.read_to_end(C::decoder(bytes)?)?;
+        F::check_limits(&bytes, limits)?;
+        let data = F::from_slice(&bytes).map_err(|e| CodecError::Format(Box::new(e)))?;
+        Ok(Compressed::new(data))
+    }
+}
+
+impl<T: Clone, C, F> Clone for Compressed<T, C, F> {
+    fn clone(&self) -> Self {
+        Compressed::new(self.data.clone())
+    }
+}
+
+impl<T: fmt::Debug, C, F> fmt::Debug for Compressed<T, C, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Compressed").field(&self.data).finish()
+    }
+}
+
+impl<T: PartialEq, C, F> PartialEq for Compressed<T, C, F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+    }
+}
+
+impl<'de, T: DeserializeOwned, C: Codec, F: Format> Deserialize<'de> for Compressed<T, C, F> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = <serde_bytes::ByteBuf>::deserialize(deserializer)?;
+        Compressed::decode(&bytes).map_err(serde::de::Error::custom)
+    }
+}
+
+impl<T: Serialize, C: Codec, F: Format> Serialize for Compressed<T, C, F> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let bytes = self.encode().map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
+impl<T> From<GzippedJSON<T>> for Compressed<T> {
+    fn from(gzipped: GzippedJSON<T>) -> Self {
+        Compressed::new(gzipped.0)
+    }
+}
+
+impl<T> From<Compressed<T>> for GzippedJSON<T> {
+    fn from(compressed: Compressed<T>) -> Self {
+        GzippedJSON(compressed.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::model::AttributeValue;
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use serde_dynamo::{from_attribute_value, to_attribute_value};
+
+    use super::{AdaptiveGzip, Codec, Compressed, Format, Gzip, Identity, Json};
+    use crate::misc::{compress, compress_with_threshold, decompress, GzippedJSON};
+
+    #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+    struct Item {
+        id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+        values: Vec<f64>,
+    }
+
+    fn item() -> Item {
+        Item {
+            id: "86d09530-0a54-11ec-b69f-cf2fbd32de70".to_string(),
+            note: None,
+            values: vec![1.5; 100],
+        }
+    }
+
+    fn round_trip<C: Codec, F: Format>()
+    where
+        Compressed<Item, C, F>: Serialize + DeserializeOwned,
+    {
+        let compressed = Compressed::<_, C, F>::new(item());
+        let attr_value: AttributeValue = to_attribute_value(compressed.clone()).unwrap();
+        assert!(attr_value.is_b());
+        let back: Compressed<Item, C, F> = from_attribute_value(attr_value).unwrap();
+        assert_eq!(back, compressed);
+    }
+
+    #[test]
+    fn codecs_and_formats() {
+        round_trip::<Gzip, Json>();
+        round_trip::<Identity, Json>();
+        round_trip::<AdaptiveGzip, Json>();
+        round_trip::<AdaptiveGzip<0>, Json>();
+        #[cfg(feature = "codec_zstd")]
+        round_trip::<super::Zstd, Json>();
+        #[cfg(feature = "codec_brotli")]
+        round_trip::<super::Brotli, Json>();
+        #[cfg(feature = "format_msgpack")]
+        round_trip::<Gzip, super::MessagePack>();
+        #[cfg(feature = "format_cbor")]
+        round_trip::<Gzip, super::Cbor>();
+    }
+
+    #[test]
+    fn gzipped_json_compatible() {
+        let compressed = Compressed::<Item>::new(item()).encode().unwrap();
+        assert_eq!(decompress::<Item>(&compressed).unwrap(), item());
+        let decoded = Compressed::<Item>::decode(&compress(item()).unwrap()).unwrap();
+        assert_eq!(decoded.data, item());
+
+        let attr_value: AttributeValue = to_attribute_value(GzippedJSON(item())).unwrap();
+        let back: Compressed<Item> = from_attribute_value(attr_value).unwrap();
+        assert_eq!(GzippedJSON::from(back).0, item());
+
+        let adaptive = Compressed::<Item, Gzip>::new(item()).encode().unwrap();
+        let decoded = Compressed::<Item, AdaptiveGzip>::decode(&adaptive).unwrap();
+        assert_eq!(decoded.data, item());
+    }
+
+    #[test]
+    fn framed_gzipped_json_compatible() {
+        // Raw and gzipped frames, as written by `compress_with_threshold`
+        for threshold in [0, usize::MAX] {
+            let framed = compress_with_threshold(item(), threshold).unwrap();
+            let attr_value = AttributeValue::B(aws_sdk_dynamodb::types::Blob::new(framed));
+            let gzipped: GzippedJSON<Item> = from_attribute_value(attr_value.clone()).unwrap();
+            let back: Compressed<Item> = from_attribute_value(attr_value).unwrap();
+            assert_eq!(back.data, gzipped.0, "{threshold}");
+            assert_eq!(back.data, item(), "{threshold}");
+        }
+    }
+}
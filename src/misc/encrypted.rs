@@ -0,0 +1,444 @@
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use aes_gcm::{
+    aead::{Aead, OsRng, Payload},
+    AeadCore, Aes256Gcm, KeyInit, Nonce,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{compress, decompress, CompressError, DecompressError};
+
+lazy_static! {
+    static ref KEY_PROVIDER: RwLock<Option<Arc<dyn KeyProvider>>> = RwLock::new(None);
+}
+
+/// Version of the binary layout written by [encrypt].
+const LAYOUT_VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+
+/// An AES-256 key.
+pub type DataKey = [u8; 32];
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("no key provider is set")]
+    NoKeyProvider,
+    #[error("unknown key id {0}")]
+    UnknownKey(String),
+    #[error("key id {0} is longer than 255 bytes")]
+    KeyIdTooLong(String),
+    #[error("invalid data key {0}, expected 32 bytes")]
+    InvalidKey(String),
+    #[error("unsupported encryption layout version {0}")]
+    UnsupportedVersion(u8),
+    #[error("encrypted data is truncated")]
+    Truncated,
+    #[error("encryption failed")]
+    Encrypt,
+    #[error("decryption failed, the data was tampered with or the key is wrong")]
+    Decrypt,
+    #[error("compress error: {0}")]
+    Compress(#[from] CompressError),
+    #[error("decompress error: {0}")]
+    Decompress(#[from] DecompressError),
+    #[cfg(feature = "services_secretsmanager")]
+    #[error("failed listing key versions: {0}")]
+    ListVersions(
+        Box<
+            aws_sdk_secretsmanager::types::SdkError<
+                aws_sdk_secretsmanager::error::ListSecretVersionIdsError,
+            >,
+        >,
+    ),
+    #[cfg(feature = "services_secretsmanager")]
+    #[error("failed fetching key: {0}")]
+    GetSecretValue(
+        Box<
+            aws_sdk_secretsmanager::types::SdkError<
+                aws_sdk_secretsmanager::error::GetSecretValueError,
+            >,
+        >,
+    ),
+    #[cfg(feature = "services_secretsmanager")]
+    #[error("the secret has no AWSCURRENT version")]
+    NoCurrentVersion,
+    #[cfg(feature = "services_kms")]
+    #[error("failed generating data key: {0}")]
+    GenerateDataKey(Box<aws_sdk_kms::types::SdkError<aws_sdk_kms::error::GenerateDataKeyError>>),
+    #[cfg(feature = "services_kms")]
+    #[error("failed unwrapping data key: {0}")]
+    UnwrapDataKey(Box<aws_sdk_kms::types::SdkError<aws_sdk_kms::error::DecryptError>>),
+    #[cfg(feature = "services_kms")]
+    #[error("no wrapped data keys given")]
+    NoWrappedKeys,
+}
+
+/// Where the data keys of [EncryptedJSON] come from.
+///
+/// Every encrypted value records the id of the key it was encrypted with, so keys can be rotated by changing
+/// [KeyProvider::current_key_id] while the previous keys are still provided for reading existing data.
+pub trait KeyProvider: Send + Sync {
+    /// The id of the key new data is encrypted with.
+    fn current_key_id(&self) -> &str;
+    fn key(&self, key_id: &str) -> Option<&DataKey>;
+}
+
+/// Keys held in memory, e.g. a static key in tests, the versions of a Secrets Manager secret or data keys unwrapped
+/// with KMS.
+#[derive(Clone)]
+pub struct KeyRing {
+    current_key_id: String,
+    keys: HashMap<String, DataKey>,
+}
+
+impl KeyRing {
+    pub fn new(key_id: impl Into<String>, key: DataKey) -> Self {
+        let current_key_id = key_id.into();
+        KeyRing {
+            keys: HashMap::from([(current_key_id.clone(), key)]),
+            current_key_id,
+        }
+    }
+
+    /// A rotated key, which is only used to decrypt existing data.
+    pub fn previous(mut self, key_id: impl Into<String>, key: DataKey) -> Self {
+        self.keys.insert(key_id.into(), key);
+        self
+    }
+
+    /// Load all versions of a secret holding a data key, either as a base64 string or as binary, with the
+    /// `AWSCURRENT` version as the current key. The version ids are the key ids.
+    ///
+    /// Deprecated versions are loaded as well, so data keeps being readable until Secrets Manager drops them.
+    #[cfg(feature = "services_secretsmanager")]
+    pub async fn from_secrets_manager(
+        secret_id: &str,
+        region: Option<&'static str>,
+    ) -> Result<Self, EncryptionError> {
+        let client = crate::services::secretsmanager::secrets_manager(region).await;
+        let mut version_ids = Vec::new();
+        let mut current_key_id = None;
+        let mut next_token = None;
+        loop {
+            let output = client
+                .list_secret_version_ids()
+                .secret_id(secret_id)
+                .include_deprecated(true)
+                .set_next_token(next_token)
+                .send()
+                .await
+                .map_err(|e| EncryptionError::ListVersions(Box::new(e)))?;
+            for version in output.versions().unwrap_or_default() {
+                let version_id = match version.version_id() {
+                    Some(version_id) => version_id.to_string(),
+                    None => continue,
+                };
+                if version
+                    .version_stages()
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|stage| stage == "AWSCURRENT")
+                {
+                    current_key_id = Some(version_id.clone());
+                }
+                version_ids.push(version_id);
+            }
+            next_token = output.next_token().map(str::to_string);
+            if next_token.is_none() {
+                break;
+            }
+        }
+        let current_key_id = current_key_id.ok_or(EncryptionError::NoCurrentVersion)?;
+
+        let mut keys = HashMap::new();
+        for version_id in version_ids {
+            let output = client
+                .get_secret_value()
+                .secret_id(secret_id)
+                .version_id(&version_id)
+                .send()
+                .await
+                .map_err(|e| EncryptionError::GetSecretValue(Box::new(e)))?;
+            let bytes = match (output.secret_binary(), output.secret_string()) {
+                (Some(binary), _) => binary.as_ref().to_vec(),
+                (None, Some(string)) => base64::decode(string.trim())
+                    .map_err(|_| EncryptionError::InvalidKey(version_id.clone()))?,
+                (None, None) => return Err(EncryptionError::InvalidKey(version_id)),
+            };
+            keys.insert(version_id.clone(), data_key(version_id, &bytes)?);
+        }
+        Ok(KeyRing {
+            current_key_id,
+            keys,
+        })
+    }
+
+    /// Unwrap data keys generated with [generate_kms_data_key] through KMS `Decrypt`, with the first key as the
+    /// current key and the others as previous keys.
+    #[cfg(feature = "services_kms")]
+    pub async fn from_kms(
+        wrapped_keys: &[WrappedKey],
+        region: Option<&'static str>,
+    ) -> Result<Self, EncryptionError> {
+        let client = crate::services::kms::kms(region).await;
+        let mut keys = HashMap::new();
+        for wrapped in wrapped_keys {
+            let output = client
+                .decrypt()
+                .ciphertext_blob(aws_sdk_kms::types::Blob::new(wrapped.ciphertext.clone()))
+                .send()
+                .await
+                .map_err(|e| EncryptionError::UnwrapDataKey(Box::new(e)))?;
+            let plaintext = output.plaintext().map_or(&[][..], |blob| blob.as_ref());
+            keys.insert(
+                wrapped.key_id.clone(),
+                data_key(&wrapped.key_id, plaintext)?,
+            );
+        }
+        let current_key_id = wrapped_keys
+            .first()
+            .ok_or(EncryptionError::NoWrappedKeys)?
+            .key_id
+            .clone();
+        Ok(KeyRing {
+            current_key_id,
+            keys,
+        })
+    }
+}
+
+#[cfg(any(feature = "services_secretsmanager", feature = "services_kms", test))]
+fn data_key(key_id: impl Into<String>, bytes: &[u8]) -> Result<DataKey, EncryptionError> {
+    DataKey::try_from(bytes).map_err(|_| EncryptionError::InvalidKey(key_id.into()))
+}
+
+/// A data key encrypted under a KMS key, to be stored (e.g. in the configuration of the service) and unwrapped
+/// with [KeyRing::from_kms] when the service starts. Only KMS can decrypt it, so it does not need to be kept secret.
+#[cfg(feature = "services_kms")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WrappedKey {
+    pub key_id: String,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Generate a new data key under the KMS key `kms_key_id` through KMS `GenerateDataKey`, to rotate to.
+///
+/// Only the wrapped key is returned; the plaintext key is obtained with [KeyRing::from_kms] where it is used.
+#[cfg(feature = "services_kms")]
+pub async fn generate_kms_data_key(
+    kms_key_id: &str,
+    key_id: impl Into<String>,
+    region: Option<&'static str>,
+) -> Result<WrappedKey, EncryptionError> {
+    let key_id = key_id.into();
+    let output = crate::services::kms::kms(region)
+        .await
+        .generate_data_key()
+        .key_id(kms_key_id)
+        .key_spec(aws_sdk_kms::model::DataKeySpec::Aes256)
+        .send()
+        .await
+        .map_err(|e| EncryptionError::GenerateDataKey(Box::new(e)))?;
+    let ciphertext = output
+        .ciphertext_blob()
+        .ok_or_else(|| EncryptionError::InvalidKey(key_id.clone()))?
+        .as_ref()
+        .to_vec();
+    Ok(WrappedKey { key_id, ciphertext })
+}
+
+impl KeyProvider for KeyRing {
+    fn current_key_id(&self) -> &str {
+        &self.current_key_id
+    }
+
+    fn key(&self, key_id: &str) -> Option<&DataKey> {
+        self.keys.get(key_id)
+    }
+}
+
+/// Set the keys [EncryptedJSON] is encrypted and decrypted with.
+pub fn set_key_provider(provider: impl KeyProvider + 'static) {
+    *KEY_PROVIDER.write().unwrap() = Some(Arc::new(provider));
+}
+
+fn key_provider() -> Result<Arc<dyn KeyProvider>, EncryptionError> {
+    KEY_PROVIDER
+        .read()
+        .unwrap()
+        .clone()
+        .ok_or(EncryptionError::NoKeyProvider)
+}
+
+/// Compress `input` like [compress] and encrypt it with AES-256-GCM using the current key of `keys`.
+///
+/// The layout is `version (1 byte) | key id length (1 byte) | key id | nonce (12 bytes) | ciphertext`, where the
+/// version and key id are authenticated as well.
+pub fn encrypt<T: Serialize>(
+    input: &T,
+    keys: &dyn KeyProvider,
+) -> Result<Vec<u8>, EncryptionError> {
+    let key_id = keys.current_key_id();
+    let key = keys
+        .key(key_id)
+        .ok_or_else(|| EncryptionError::UnknownKey(key_id.to_string()))?;
+    let key_id_length = u8::try_from(key_id.len())
+        .map_err(|_| EncryptionError::KeyIdTooLong(key_id.to_string()))?;
+
+    let mut output = vec![LAYOUT_VERSION, key_id_length];
+    output.extend_from_slice(key_id.as_bytes());
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(key.into())
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &compress(input)?,
+                aad: &output,
+            },
+        )
+        .map_err(|_| EncryptionError::Encrypt)?;
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&ciphertext);
+    Ok(output)
+}
+
+/// Decrypt data written by [encrypt], with the key it was encrypted with.
+pub fn decrypt<T: DeserializeOwned>(
+    input: &[u8],
+    keys: &dyn KeyProvider,
+) -> Result<T, EncryptionError> {
+    let (&version, rest) = input.split_first().ok_or(EncryptionError::Truncated)?;
+    if version != LAYOUT_VERSION {
+        return Err(EncryptionError::UnsupportedVersion(version));
+    }
+    let (&key_id_length, rest) = rest.split_first().ok_or(EncryptionError::Truncated)?;
+    let header_length = 2 + key_id_length as usize;
+    if input.len() < header_length + NONCE_LENGTH {
+        return Err(EncryptionError::Truncated);
+    }
+    let key_id = String::from_utf8_lossy(&rest[..key_id_length as usize]);
+    let key = keys
+        .key(&key_id)
+        .ok_or_else(|| EncryptionError::UnknownKey(key_id.to_string()))?;
+
+    let (header, rest) = input.split_at(header_length);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+    let compressed = Aes256Gcm::new(key.into())
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| EncryptionError::Decrypt)?;
+    Ok(decompress(&compressed)?)
+}
+
+/// Like [super::GzippedJSON], but encrypted with the keys set with [set_key_provider], see [encrypt].
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncryptedJSON<T>(pub T);
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for EncryptedJSON<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = <serde_bytes::ByteBuf>::deserialize(deserializer)?;
+        let keys = key_provider().map_err(serde::de::Error::custom)?;
+        Ok(EncryptedJSON(
+            decrypt(&bytes, keys.as_ref()).map_err(serde::de::Error::custom)?,
+        ))
+    }
+}
+
+impl<T: Serialize> Serialize for EncryptedJSON<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let keys = key_provider().map_err(serde::ser::Error::custom)?;
+        let bytes = encrypt(&self.0, keys.as_ref()).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::model::AttributeValue;
+    use serde_dynamo::{from_attribute_value, to_attribute_value};
+    use serde_json::{json, Value};
+
+    use super::{
+        data_key, decrypt, encrypt, set_key_provider, EncryptedJSON, EncryptionError, KeyRing,
+    };
+
+    fn value() -> Value {
+        json!({"apiKey": "e6c3a7b2", "values": [1, 2, 3]})
+    }
+
+    #[test]
+    fn key_rotation() {
+        let old_keys = KeyRing::new("2022-01", [1; 32]);
+        let encrypted = encrypt(&value(), &old_keys).unwrap();
+        assert_eq!(&encrypted[..9], b"\x01\x072022-01");
+
+        let rotated = KeyRing::new("2022-06", [2; 32]).previous("2022-01", [1; 32]);
+        assert_eq!(decrypt::<Value>(&encrypted, &rotated).unwrap(), value());
+        let reencrypted = encrypt(&value(), &rotated).unwrap();
+        assert_eq!(&reencrypted[2..9], b"2022-06");
+        assert!(matches!(
+            decrypt::<Value>(&reencrypted, &old_keys),
+            Err(EncryptionError::UnknownKey(key_id)) if key_id == "2022-06"
+        ));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let keys = KeyRing::new("a", [1; 32]).previous("b", [1; 32]);
+        let encrypted = encrypt(&value(), &keys).unwrap();
+
+        let mut flipped = encrypted.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decrypt::<Value>(&flipped, &keys),
+            Err(EncryptionError::Decrypt)
+        ));
+        // The key id is authenticated, even when the other key would decrypt the ciphertext
+        let mut other_key_id = encrypted.clone();
+        other_key_id[2] = b'b';
+        assert!(matches!(
+            decrypt::<Value>(&other_key_id, &keys),
+            Err(EncryptionError::Decrypt)
+        ));
+        assert!(matches!(
+            decrypt::<Value>(&encrypted[..10], &keys),
+            Err(EncryptionError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn data_keys_must_be_256_bits() {
+        assert_eq!(data_key("v1", &[3; 32]).unwrap(), [3; 32]);
+        for bytes in [&[3; 16][..], &[3; 33][..], &[]] {
+            assert!(matches!(
+                data_key("v1", bytes),
+                Err(EncryptionError::InvalidKey(key_id)) if key_id == "v1"
+            ));
+        }
+    }
+
+    #[test]
+    fn dynamodb_attribute() {
+        set_key_provider(KeyRing::new("test", [7; 32]));
+        let attr_value: AttributeValue = to_attribute_value(EncryptedJSON(value())).unwrap();
+        assert!(attr_value.is_b());
+        let back: EncryptedJSON<Value> = from_attribute_value(attr_value).unwrap();
+        assert_eq!(back.0, value());
+    }
+}
@@ -0,0 +1,362 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use env_logger::filter::{Builder, Filter};
+use log::{Level, LevelFilter, Metadata};
+use thiserror::Error;
+
+use super::RequestContext;
+
+lazy_static! {
+    static ref ENV_LOG_FILTER: Result<LogFilter, LogFilterError> = LogFilter::from_env();
+    static ref LOG_FILTER: RwLock<Arc<LogFilter>> = RwLock::new(Arc::new(
+        ENV_LOG_FILTER.as_ref().cloned().unwrap_or_default()
+    ));
+    /// `None` until a logger of this module is installed, after that the number of requests in scope that have
+    /// debug enabled. The lock is held while updating `log::max_level`, so concurrent updates cannot interleave.
+    static ref DEBUG_REQUESTS: Mutex<Option<usize>> = Mutex::new(None);
+}
+
+/// Env var the initial [LogFilter] is read from.
+pub const LOG_FILTER_ENV: &str = "RUST_LOG";
+
+#[derive(Error, Debug)]
+pub enum LogFilterError {
+    #[error("invalid log level `{0}`")]
+    InvalidLevel(String),
+    #[error("invalid debug sample percentage `{0}`")]
+    InvalidSamplePercentage(String),
+    #[error("invalid message filter: {0}")]
+    InvalidMessageFilter(String),
+    #[cfg(feature = "services_ssm")]
+    #[error("failed fetching log filter: {0}")]
+    Ssm(Box<aws_sdk_ssm::types::SdkError<aws_sdk_ssm::error::GetParameterError>>),
+    #[cfg(feature = "services_ssm")]
+    #[error("log filter parameter has no value")]
+    EmptyParameter,
+}
+
+/// Decides which log lines are written, per target and per request.
+///
+/// Parsed from the [env_logger] syntax, e.g. `warn,bb_rust=info,bb_rust::graphql=debug,hyper=off/timeout`, with the
+/// addition of a `debug_sample=<percent>` directive:
+/// - `<level>` sets the level of targets without a more specific directive (`error` if not set)
+/// - `<target>=<level>` sets the level of the targets starting with `<target>`, `<target>` alone enables all levels
+/// - `/<regex>` only writes lines whose message matches the regex
+/// - `debug_sample=<percent>` enables DEBUG for that percentage of requests, picked by request id
+///
+/// DEBUG is also enabled for requests whose [RequestContext] has [RequestContext::with_debug] set.
+/// Targets that are turned `off` stay off for sampled and debugged requests.
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    /// The directives without `debug_sample`, as given to `filter`
+    directives: String,
+    filter: Arc<Filter>,
+    message: Option<Regex>,
+    debug_sample_percent: f64,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            directives: String::new(),
+            filter: Arc::new(build_filter("")),
+            message: None,
+            debug_sample_percent: 0.0,
+        }
+    }
+}
+
+impl PartialEq for LogFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.directives == other.directives
+            && self.message.as_ref().map(Regex::as_str) == other.message.as_ref().map(Regex::as_str)
+            && self.debug_sample_percent == other.debug_sample_percent
+    }
+}
+
+fn build_filter(directives: &str) -> Filter {
+    Builder::new()
+        .filter_level(LevelFilter::Error)
+        .parse(directives)
+        .build()
+}
+
+impl FromStr for LogFilter {
+    type Err = LogFilterError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (spec, message) = match spec.split_once('/') {
+            Some((spec, message)) => (spec, Some(message)),
+            None => (spec, None),
+        };
+        let mut debug_sample_percent = 0.0;
+        let mut directives = Vec::new();
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some(("debug_sample", percent)) => {
+                    debug_sample_percent = percent
+                        .trim_end_matches('%')
+                        .parse()
+                        .ok()
+                        .filter(|percent| (0.0..=100.0).contains(percent))
+                        .ok_or_else(|| {
+                            LogFilterError::InvalidSamplePercentage(percent.to_string())
+                        })?;
+                }
+                // env_logger would skip an invalid level with a warning on stderr
+                Some((_, level)) if !level.is_empty() => {
+                    parse_level(level.trim())?;
+                    directives.push(directive);
+                }
+                _ => directives.push(directive),
+            }
+        }
+        let directives = directives.join(",");
+        Ok(LogFilter {
+            filter: Arc::new(build_filter(&directives)),
+            directives,
+            message: message
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| LogFilterError::InvalidMessageFilter(e.to_string()))?,
+            debug_sample_percent,
+        })
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, LogFilterError> {
+    level
+        .parse()
+        .map_err(|_| LogFilterError::InvalidLevel(level.to_string()))
+}
+
+impl LogFilter {
+    /// Parse the directives in [LOG_FILTER_ENV], or the default filter if it is not set.
+    pub fn from_env() -> Result<Self, LogFilterError> {
+        std::env::var(LOG_FILTER_ENV)
+            .map(|directives| directives.parse())
+            .unwrap_or_else(|_| Ok(LogFilter::default()))
+    }
+
+    /// Parse the directives stored in an SSM parameter.
+    #[cfg(feature = "services_ssm")]
+    pub async fn from_ssm(
+        client: &aws_sdk_ssm::Client,
+        parameter_name: &str,
+    ) -> Result<Self, LogFilterError> {
+        let output = client
+            .get_parameter()
+            .name(parameter_name)
+            .send()
+            .await
+            .map_err(|e| LogFilterError::Ssm(Box::new(e)))?;
+        output
+            .parameter()
+            .and_then(|parameter| parameter.value())
+            .ok_or(LogFilterError::EmptyParameter)?
+            .parse()
+    }
+
+    pub fn debug_sample_percent(&self) -> f64 {
+        self.debug_sample_percent
+    }
+
+    /// The highest level that can be enabled for any target, which is at least DEBUG when requests are sampled.
+    ///
+    /// Requests with [RequestContext::with_debug] set raise `log::max_level` to DEBUG only while they are in scope.
+    pub fn max_level(&self) -> LevelFilter {
+        let max_level = self.filter.filter();
+        if self.debug_sample_percent > 0.0 {
+            max_level.max(LevelFilter::Debug)
+        } else {
+            max_level
+        }
+    }
+
+    pub fn enabled(&self, target: &str, level: Level, context: Option<&RequestContext>) -> bool {
+        let enabled = |level| {
+            self.filter
+                .enabled(&Metadata::builder().target(target).level(level).build())
+        };
+        enabled(level)
+            || (level <= Level::Debug
+                // Not turned `off`
+                && enabled(Level::Error)
+                && context.is_some_and(|context| self.debug_enabled(context)))
+    }
+
+    /// Whether a line with `message` passes the `/<regex>` of the filter, if any.
+    pub fn message_enabled(&self, message: &str) -> bool {
+        self.message
+            .as_ref()
+            .is_none_or(|message_filter| message_filter.is_match(message))
+    }
+
+    fn debug_enabled(&self, context: &RequestContext) -> bool {
+        let bucket = (fnv1a(context.request_id()) % 10_000) as f64;
+        context.debug() || bucket < self.debug_sample_percent * 100.0
+    }
+}
+
+/// A hash that is stable across processes, so every service samples the same requests.
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Replace the filter used by the loggers set up by this module, e.g. after reading a new filter from SSM.
+pub fn set_log_filter(filter: LogFilter) {
+    *LOG_FILTER.write().unwrap() = Arc::new(filter);
+    update_max_level(|_| {});
+}
+
+/// Let `log::max_level` follow the current filter and debugged requests, once a logger of this module is installed.
+pub(super) fn manage_max_level() {
+    update_max_level(|debug_requests| {
+        debug_requests.get_or_insert(0);
+    });
+}
+
+fn update_max_level(update: impl FnOnce(&mut Option<usize>)) {
+    let mut debug_requests = DEBUG_REQUESTS.lock().unwrap();
+    update(&mut debug_requests);
+    if let Some(debug_requests) = *debug_requests {
+        let max_level = log_filter().max_level();
+        log::set_max_level(if debug_requests > 0 {
+            max_level.max(LevelFilter::Debug)
+        } else {
+            max_level
+        });
+    }
+}
+
+/// Keeps `log::max_level` at DEBUG or above while a request with debug enabled is in scope.
+pub(super) struct DebugRequest {
+    /// Whether the request was counted, i.e. started after a logger was installed
+    counted: bool,
+}
+
+impl DebugRequest {
+    pub(super) fn start() -> Self {
+        let mut counted = false;
+        update_max_level(|debug_requests| {
+            if let Some(debug_requests) = debug_requests {
+                *debug_requests += 1;
+                counted = true;
+            }
+        });
+        DebugRequest { counted }
+    }
+}
+
+impl Drop for DebugRequest {
+    fn drop(&mut self) {
+        if self.counted {
+            update_max_level(|debug_requests| {
+                if let Some(debug_requests) = debug_requests {
+                    *debug_requests -= 1;
+                }
+            });
+        }
+    }
+}
+
+pub(super) fn log_filter() -> Arc<LogFilter> {
+    LOG_FILTER.read().unwrap().clone()
+}
+
+/// Why the directives in [LOG_FILTER_ENV] were ignored in favor of the default filter, to be logged once a logger
+/// is installed.
+pub(super) fn env_log_filter_error() -> Option<&'static LogFilterError> {
+    ENV_LOG_FILTER.as_ref().err()
+}
+
+#[cfg(test)]
+mod tests {
+    use log::{Level, LevelFilter};
+
+    use super::{manage_max_level, LogFilter};
+    use crate::misc::RequestContext;
+
+    #[test]
+    fn parse_directives() {
+        let filter: LogFilter =
+            "warn, bb_rust=info,bb_rust::graphql=debug,hyper=off,debug_sample=5%"
+                .parse()
+                .unwrap();
+        assert!(filter.enabled("bb_rust::graphql::internal", Level::Debug, None));
+        assert!(!filter.enabled("bb_rust::graphql::internal", Level::Trace, None));
+        assert!(filter.enabled("bb_rust::misc", Level::Info, None));
+        assert!(!filter.enabled("bb_rust::misc", Level::Debug, None));
+        // Targets are matched by prefix, like in env_logger
+        assert!(filter.enabled("bb_rust_other", Level::Info, None));
+        assert!(!filter.enabled("other", Level::Info, None));
+        assert!(filter.enabled("other", Level::Warn, None));
+        assert!(!filter.enabled("hyper::client", Level::Error, None));
+        assert_eq!(filter.debug_sample_percent(), 5.0);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        assert!("info,bb_rust=loud".parse::<LogFilter>().is_err());
+        assert!("debug_sample=150".parse::<LogFilter>().is_err());
+        assert!("info/(unclosed".parse::<LogFilter>().is_err());
+        assert_eq!("".parse::<LogFilter>().unwrap(), LogFilter::default());
+        assert!(!LogFilter::default().enabled("bb_rust", Level::Warn, None));
+        assert!(LogFilter::default().enabled("bb_rust", Level::Error, None));
+    }
+
+    #[test]
+    fn message_filter() {
+        let filter: LogFilter = "info,hyper=off/time(out|d out)".parse().unwrap();
+        assert!(filter.enabled("bb_rust", Level::Info, None));
+        assert!(!filter.enabled("hyper", Level::Error, None));
+        assert!(filter.message_enabled("request timed out"));
+        assert!(filter.message_enabled("timeout after 3s"));
+        assert!(!filter.message_enabled("request done"));
+        assert!(LogFilter::default().message_enabled("request done"));
+    }
+
+    #[test]
+    fn max_level_only_raised_for_sampling() {
+        assert_eq!(LogFilter::default().max_level(), LevelFilter::Error);
+        let filter: LogFilter = "warn,bb_rust=info".parse().unwrap();
+        assert_eq!(filter.max_level(), LevelFilter::Info);
+        let filter: LogFilter = "warn,debug_sample=1".parse().unwrap();
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        manage_max_level();
+        RequestContext::new("req".to_string())
+            .with_debug(true)
+            .sync_scope(|| assert!(log::max_level() >= LevelFilter::Debug));
+    }
+
+    #[test]
+    fn forced_debug() {
+        let filter: LogFilter = "info,hyper=off".parse().unwrap();
+        let context = RequestContext::new("req".to_string());
+        assert!(!filter.enabled("bb_rust", Level::Debug, Some(&context)));
+        let context = context.with_debug(true);
+        assert!(filter.enabled("bb_rust", Level::Debug, Some(&context)));
+        assert!(!filter.enabled("bb_rust", Level::Trace, Some(&context)));
+        assert!(!filter.enabled("hyper", Level::Debug, Some(&context)));
+    }
+
+    #[test]
+    fn debug_sampling() {
+        let filter: LogFilter = "info,debug_sample=10".parse().unwrap();
+        let sampled = (0..10_000)
+            .map(|i| RequestContext::new(format!("request-{}", i)))
+            .filter(|context| filter.enabled("bb_rust", Level::Debug, Some(context)))
+            .collect::<Vec<_>>();
+        assert!((800..1200).contains(&sampled.len()), "{}", sampled.len());
+        // The same request is always sampled
+        assert!(sampled
+            .iter()
+            .all(|context| filter.enabled("other", Level::Debug, Some(context))));
+    }
+}
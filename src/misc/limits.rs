@@ -0,0 +1,238 @@
+use lazy_static::lazy_static;
+use std::{
+    fmt,
+    io::{self, Read},
+    sync::RwLock,
+};
+
+lazy_static! {
+    static ref DECOMPRESS_LIMITS: RwLock<DecompressLimits> =
+        RwLock::new(DecompressLimits::default());
+}
+
+/// Upper bounds applied when decompressing untrusted data, so a small payload cannot inflate into enough data to
+/// exhaust the memory of the process.
+///
+/// The limits used by [super::decompress], [super::GzippedJSON] and [super::Compressed] are set process wide with
+/// [set_decompress_limits].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecompressLimits {
+    max_size: usize,
+    max_depth: Option<usize>,
+}
+
+/// Default of [DecompressLimits::max_size].
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+/// Default of [DecompressLimits::max_depth].
+pub const DEFAULT_MAX_JSON_DEPTH: usize = 64;
+
+impl Default for DecompressLimits {
+    /// [DEFAULT_MAX_DECOMPRESSED_SIZE] (16 MiB), which is above what a Lambda can receive or return (6 MB) while
+    /// leaving room in a 128 MB Lambda, and [DEFAULT_MAX_JSON_DEPTH] (64), which is reported before the recursion
+    /// limit of serde_json (128) is reached.
+    fn default() -> Self {
+        DecompressLimits {
+            max_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+            max_depth: Some(DEFAULT_MAX_JSON_DEPTH),
+        }
+    }
+}
+
+impl DecompressLimits {
+    /// Maximum number of bytes the data may decompress to.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Maximum nesting of JSON arrays and objects.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Only limit the nesting of JSON by the recursion limit of serde_json.
+    pub fn unlimited_depth(mut self) -> Self {
+        self.max_depth = None;
+        self
+    }
+
+    pub(super) fn read_to_end(&self, reader: impl Read) -> Result<Vec<u8>, LimitError> {
+        let mut bytes = Vec::new();
+        // Read one byte more than allowed, to know whether the data would exceed the limit
+        reader
+            .take(self.max_size as u64 + 1)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() > self.max_size {
+            return Err(LimitError::Exceeded(LimitExceeded::Size(self.max_size)));
+        }
+        Ok(bytes)
+    }
+
+    /// Wrap `reader` so that reading more than [DecompressLimits::max_size] bytes fails with a [LimitExceeded]
+    /// io error, see [limit_exceeded].
+    pub(super) fn limit_reader<R: Read>(&self, reader: R) -> LimitedReader<R> {
+        LimitedReader {
+            inner: reader,
+            remaining: self.max_size,
+            max_size: self.max_size,
+        }
+    }
+
+    pub(super) fn check_json_depth(&self, json: &[u8]) -> Result<(), LimitExceeded> {
+        let max_depth = match self.max_depth {
+            Some(max_depth) => max_depth,
+            None => return Ok(()),
+        };
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        for &byte in json {
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => in_string = true,
+                b'[' | b'{' => {
+                    depth += 1;
+                    if depth > max_depth {
+                        return Err(LimitExceeded::Depth(max_depth));
+                    }
+                }
+                b']' | b'}' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the limits applied by all decompression in the process.
+pub fn set_decompress_limits(limits: DecompressLimits) {
+    *DECOMPRESS_LIMITS.write().unwrap() = limits;
+}
+
+pub fn decompress_limits() -> DecompressLimits {
+    *DECOMPRESS_LIMITS.read().unwrap()
+}
+
+/// Which of the [DecompressLimits] was exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// The data decompresses to more than this many bytes
+    Size(usize),
+    /// The JSON is nested deeper than this
+    Depth(usize),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Size(max_size) => write!(f, "more than {} bytes", max_size),
+            LimitExceeded::Depth(max_depth) => write!(f, "nested deeper than {}", max_depth),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+pub(super) enum LimitError {
+    Io(io::Error),
+    Exceeded(LimitExceeded),
+}
+
+impl From<io::Error> for LimitError {
+    fn from(e: io::Error) -> Self {
+        match limit_exceeded(&e) {
+            Some(exceeded) => LimitError::Exceeded(exceeded),
+            None => LimitError::Io(e),
+        }
+    }
+}
+
+/// The [LimitExceeded] a [LimitedReader] failed with.
+pub(super) fn limit_exceeded(e: &io::Error) -> Option<LimitExceeded> {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<LimitExceeded>())
+        .copied()
+}
+
+pub(super) struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+    max_size: usize,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            // Only fail if there actually is more data
+            let read = self.inner.read(&mut [0])?;
+            return if read == 0 {
+                Ok(0)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    LimitExceeded::Size(self.max_size),
+                ))
+            };
+        }
+        let len = buf.len().min(self.remaining);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.remaining -= read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{limit_exceeded, DecompressLimits, LimitExceeded, DEFAULT_MAX_JSON_DEPTH};
+
+    #[test]
+    fn json_depth() {
+        let limits = DecompressLimits::default().max_depth(2);
+        assert_eq!(
+            limits.check_json_depth(br#"{"a": [1, 2], "b": {}}"#),
+            Ok(())
+        );
+        assert_eq!(
+            limits.check_json_depth(br#"{"a": [[1]]}"#),
+            Err(LimitExceeded::Depth(2))
+        );
+        assert_eq!(limits.check_json_depth(br#"["[[[\"{{"]"#), Ok(()));
+        assert_eq!(
+            DecompressLimits::default().check_json_depth(&[b'['; 1000]),
+            Err(LimitExceeded::Depth(DEFAULT_MAX_JSON_DEPTH))
+        );
+        assert_eq!(
+            DecompressLimits::default()
+                .unlimited_depth()
+                .check_json_depth(&[b'['; 1000]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn limited_reader() {
+        let limits = DecompressLimits::default().max_size(4);
+        let mut bytes = Vec::new();
+        limits
+            .limit_reader(&b"1234"[..])
+            .read_to_end(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes, b"1234");
+        let e = limits
+            .limit_reader(&b"12345"[..])
+            .read_to_end(&mut bytes)
+            .unwrap_err();
+        assert_eq!(limit_exceeded(&e), Some(LimitExceeded::Size(4)));
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    any::Any,
+    convert::Infallible,
+    fmt::Display,
+    future::Future,
+    marker::PhantomData,
+    panic::{catch_unwind, AssertUnwindSafe},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use super::{decompress, DecompressError, RequestContext};
+
+/// Handles a single invocation.
+///
+/// Implemented for every `Fn(Req) -> impl Future<Output = Result<R, E>> + Send`, so plain async functions and
+/// closures can be used as handlers and wrapped with [Layer]s through [HandlerExt::with].
+///
+/// The returned future is `Send`, so handlers can be spawned on a multi-threaded runtime.
+pub trait Handler<Req> {
+    type Response;
+    type Error;
+
+    fn call(
+        &self,
+        request: Req,
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> + Send;
+}
+
+impl<Req, R, E, F, Fut> Handler<Req> for F
+where
+    F: Fn(Req) -> Fut,
+    Fut: Future<Output = Result<R, E>> + Send,
+{
+    type Response = R;
+    type Error = E;
+
+    fn call(&self, request: Req) -> impl Future<Output = Result<R, E>> + Send {
+        self(request)
+    }
+}
+
+/// Wraps a [Handler] into another, e.g. to run code before and after every invocation.
+pub trait Layer<H> {
+    type Handler;
+
+    fn layer(&self, inner: H) -> Self::Handler;
+}
+
+pub trait HandlerExt: Sized {
+    /// Wrap the handler with `layer`. The last layer added runs first.
+    ///
+    /// ```ignore
+    /// let handler = handle
+    ///     .with(DecodePayload::<MyEvent>::new())
+    ///     .with(CatchPanic)
+    ///     .with(Timeout::new(Duration::from_millis(500)));
+    /// ```
+    fn with<L: Layer<Self>>(self, layer: L) -> L::Handler {
+        layer.layer(self)
+    }
+}
+
+impl<H> HandlerExt for H {}
+
+/// Runs the handler in a [RequestContext] built from the request, e.g. to add the user sub to the logs or to
+/// force debug logs through [super::DEBUG_HEADER].
+///
+/// The context starts from the current one, as set up by the [super::Runtime], or a default context otherwise.
+pub struct LogContext<F> {
+    enrich: F,
+}
+
+impl<F> LogContext<F> {
+    pub fn new(enrich: F) -> Self {
+        LogContext { enrich }
+    }
+}
+
+impl<H, F: Clone> Layer<H> for LogContext<F> {
+    type Handler = LogContextHandler<H, F>;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        LogContextHandler {
+            inner,
+            enrich: self.enrich.clone(),
+        }
+    }
+}
+
+pub struct LogContextHandler<H, F> {
+    inner: H,
+    enrich: F,
+}
+
+impl<Req, H, F> Handler<Req> for LogContextHandler<H, F>
+where
+    Req: Send,
+    H: Handler<Req> + Sync,
+    F: Fn(&Req, RequestContext) -> RequestContext + Sync,
+{
+    type Response = H::Response;
+    type Error = H::Error;
+
+    async fn call(&self, request: Req) -> Result<Self::Response, Self::Error> {
+        let context = (self.enrich)(&request, RequestContext::current().unwrap_or_default());
+        context.scope(self.inner.call(request)).await
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PayloadError {
+    #[error("payload is not base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("bad payload: {0}")]
+    Decompress(#[from] DecompressError),
+}
+
+/// Decodes a `base64(gzip(toJson(data)))` payload, as sent by our services, before passing the data to the
+/// handler. This is the base64 encoded form of [super::GzippedJSON].
+pub struct DecodePayload<T>(PhantomData<fn() -> T>);
+
+impl<T> DecodePayload<T> {
+    pub fn new() -> Self {
+        DecodePayload(PhantomData)
+    }
+}
+
+impl<T> Default for DecodePayload<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H, T> Layer<H> for DecodePayload<T> {
+    type Handler = DecodePayloadHandler<H, T>;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        DecodePayloadHandler(inner, PhantomData)
+    }
+}
+
+pub struct DecodePayloadHandler<H, T>(H, PhantomData<fn() -> T>);
+
+impl<H, T> Handler<String> for DecodePayloadHandler<H, T>
+where
+    T: DeserializeOwned,
+    H: Handler<T> + Sync,
+    H::Error: From<PayloadError>,
+{
+    type Response = H::Response;
+    type Error = H::Error;
+
+    async fn call(&self, payload: String) -> Result<Self::Response, Self::Error> {
+        let bytes = base64::decode(payload.trim_matches('"')).map_err(PayloadError::from)?;
+        let data = decompress(&bytes).map_err(PayloadError::from)?;
+        self.0.call(data).await
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("handler panicked: {0}")]
+pub struct Panicked(pub String);
+
+/// Turns a panic in the handler into a [Panicked] error, so the invocation fails with a proper error response.
+pub struct CatchPanic;
+
+impl<H> Layer<H> for CatchPanic {
+    type Handler = CatchPanicHandler<H>;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        CatchPanicHandler(inner)
+    }
+}
+
+pub struct CatchPanicHandler<H>(H);
+
+impl<Req, H> Handler<Req> for CatchPanicHandler<H>
+where
+    Req: Send,
+    H: Handler<Req> + Sync,
+    H::Error: From<Panicked>,
+{
+    type Response = H::Response;
+    type Error = H::Error;
+
+    async fn call(&self, request: Req) -> Result<Self::Response, Self::Error> {
+        let future = catch_unwind(AssertUnwindSafe(|| self.0.call(request)))
+            .map_err(|panic| Panicked(panic_message(panic)))?;
+        CatchUnwind(Box::pin(future))
+            .await
+            .map_err(|panic| Panicked(panic_message(panic)))?
+    }
+}
+
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.0.as_mut();
+        match catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .unwrap_or_else(|| "unknown panic".to_string()),
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("handler did not finish before the invocation deadline")]
+pub struct TimedOut;
+
+/// Fails the invocation with [TimedOut] `margin` before the deadline of the current [RequestContext], so the
+/// handler gets to report an error instead of being killed by Lambda. Handlers without a deadline are not limited.
+pub struct Timeout {
+    margin: Duration,
+}
+
+impl Timeout {
+    pub fn new(margin: Duration) -> Self {
+        Timeout { margin }
+    }
+}
+
+impl<H> Layer<H> for Timeout {
+    type Handler = TimeoutHandler<H>;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        TimeoutHandler {
+            inner,
+            margin: self.margin,
+        }
+    }
+}
+
+pub struct TimeoutHandler<H> {
+    inner: H,
+    margin: Duration,
+}
+
+impl<Req, H> Handler<Req> for TimeoutHandler<H>
+where
+    Req: Send,
+    H: Handler<Req> + Sync,
+    H::Error: From<TimedOut>,
+{
+    type Response = H::Response;
+    type Error = H::Error;
+
+    async fn call(&self, request: Req) -> Result<Self::Response, Self::Error> {
+        let remaining = RequestContext::current()
+            .and_then(|context| context.deadline())
+            .map(|deadline| {
+                deadline
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .saturating_sub(self.margin)
+            });
+        match remaining {
+            Some(remaining) => tokio::time::timeout(remaining, self.inner.call(request))
+                .await
+                .map_err(|_| TimedOut)?,
+            None => self.inner.call(request).await,
+        }
+    }
+}
+
+/// Logs handler errors and turns them into a response, e.g. an API Gateway response with a 500 status code.
+pub struct ErrorToResponse<F> {
+    map: F,
+}
+
+impl<F> ErrorToResponse<F> {
+    pub fn new(map: F) -> Self {
+        ErrorToResponse { map }
+    }
+}
+
+impl<H, F: Clone> Layer<H> for ErrorToResponse<F> {
+    type Handler = ErrorToResponseHandler<H, F>;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        ErrorToResponseHandler {
+            inner,
+            map: self.map.clone(),
+        }
+    }
+}
+
+pub struct ErrorToResponseHandler<H, F> {
+    inner: H,
+    map: F,
+}
+
+impl<Req, H, F> Handler<Req> for ErrorToResponseHandler<H, F>
+where
+    Req: Send,
+    H: Handler<Req> + Sync,
+    H::Error: Display,
+    F: Fn(H::Error) -> H::Response + Sync,
+{
+    type Response = H::Response;
+    type Error = Infallible;
+
+    async fn call(&self, request: Req) -> Result<Self::Response, Self::Error> {
+        Ok(self.inner.call(request).await.unwrap_or_else(|e| {
+            log::error!("Invocation failed: {}", e);
+            (self.map)(e)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use serde::{Deserialize, Serialize};
+    use thiserror::Error;
+
+    use super::{
+        CatchPanic, DecodePayload, ErrorToResponse, Handler, HandlerExt, LogContext, Panicked,
+        PayloadError, TimedOut, Timeout,
+    };
+    use crate::misc::{compress, RequestContext};
+
+    #[derive(Error, Debug)]
+    enum Error {
+        #[error(transparent)]
+        Payload(#[from] PayloadError),
+        #[error(transparent)]
+        Panicked(#[from] Panicked),
+        #[error(transparent)]
+        TimedOut(#[from] TimedOut),
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct Event {
+        name: String,
+        sleep_ms: u64,
+    }
+
+    async fn greet(event: Event) -> Result<String, Error> {
+        tokio::time::sleep(Duration::from_millis(event.sleep_ms)).await;
+        if event.name.is_empty() {
+            panic!("no name");
+        }
+        let user = RequestContext::current()
+            .and_then(|context| context.user_sub().map(str::to_string))
+            .unwrap_or_default();
+        Ok(format!("Hello {} from {}", event.name, user))
+    }
+
+    fn payload(name: &str, sleep_ms: u64) -> String {
+        let event = Event {
+            name: name.to_string(),
+            sleep_ms,
+        };
+        base64::encode(compress(event).unwrap())
+    }
+
+    #[tokio::test]
+    async fn layers() {
+        let handler = greet
+            .with(DecodePayload::<Event>::new())
+            .with(CatchPanic)
+            .with(Timeout::new(Duration::from_millis(50)))
+            .with(LogContext::new(|_: &String, context: RequestContext| {
+                context.with_user_sub("sub".to_string())
+            }));
+        let context = RequestContext::new("req".to_string())
+            .with_deadline(SystemTime::now() + Duration::from_millis(150));
+
+        let result = context
+            .clone()
+            .scope(handler.call(payload("world", 0)))
+            .await;
+        assert_eq!(result.unwrap(), "Hello world from sub");
+
+        let result = context.clone().scope(handler.call(payload("", 0))).await;
+        assert!(matches!(result, Err(Error::Panicked(Panicked(message))) if message == "no name"));
+
+        let result = context
+            .clone()
+            .scope(handler.call(payload("world", 200)))
+            .await;
+        assert!(matches!(result, Err(Error::TimedOut(_))));
+
+        let result = context.scope(handler.call("not base64!".to_string())).await;
+        assert!(matches!(
+            result,
+            Err(Error::Payload(PayloadError::Base64(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn layered_handlers_can_be_spawned() {
+        fn assert_send<T: Send + Sync>(_: &T) {}
+
+        let handler = greet
+            .with(DecodePayload::<Event>::new())
+            .with(CatchPanic)
+            .with(Timeout::new(Duration::from_millis(50)))
+            .with(LogContext::new(|_: &String, context: RequestContext| {
+                context.with_user_sub("sub".to_string())
+            }))
+            .with(ErrorToResponse::new(|e: Error| format!("failed: {}", e)));
+        assert_send(&handler);
+        // Only compiles if the future of the layered handler is `Send`
+        let response = tokio::spawn(async move { handler.call(payload("world", 0)).await })
+            .await
+            .unwrap();
+        assert_eq!(response.unwrap(), "Hello world from sub");
+    }
+
+    #[tokio::test]
+    async fn error_to_response() {
+        let handler = greet
+            .with(CatchPanic)
+            .with(ErrorToResponse::new(|e: Error| format!("failed: {}", e)));
+        let event = Event {
+            name: String::new(),
+            sleep_ms: 0,
+        };
+        assert_eq!(
+            handler.call(event).await.unwrap(),
+            "failed: handler panicked: no name"
+        );
+    }
+}
@@ -0,0 +1,597 @@
+use std::io::{BufRead, Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+mod compressed;
+#[cfg(feature = "encryption")]
+mod encrypted;
+mod filter;
+mod limits;
+mod logging;
+mod middleware;
+mod redact;
+mod request_context;
+#[cfg(feature = "runtime")]
+mod runtime;
+mod streaming;
+#[cfg(feature = "telemetry")]
+mod telemetry;
+mod versioned;
+
+#[cfg(feature = "codec_brotli")]
+pub use compressed::Brotli;
+#[cfg(feature = "codec_zstd")]
+pub use compressed::Zstd;
+pub use compressed::{AdaptiveGzip, Codec, CodecError, Compressed, Format, Gzip, Identity, Json};
+#[cfg(feature = "format_cbor")]
+pub use compressed::{Cbor, CborError};
+#[cfg(feature = "format_msgpack")]
+pub use compressed::{MessagePack, MessagePackError};
+#[cfg(feature = "encryption")]
+pub use encrypted::{
+    decrypt, encrypt, set_key_provider, DataKey, EncryptedJSON, EncryptionError, KeyProvider,
+    KeyRing,
+};
+#[cfg(all(feature = "encryption", feature = "services_kms"))]
+pub use encrypted::{generate_kms_data_key, WrappedKey};
+pub use filter::{set_log_filter, LogFilter, LogFilterError, LOG_FILTER_ENV};
+pub use limits::{
+    decompress_limits, set_decompress_limits, DecompressLimits, LimitExceeded,
+    DEFAULT_MAX_DECOMPRESSED_SIZE, DEFAULT_MAX_JSON_DEPTH,
+};
+#[allow(deprecated)]
+pub use logging::setup_aws_lambda_logging;
+pub use logging::{setup_lambda_logging, LambdaLoggerBuilder, LogFormat, LOG_FORMAT_ENV};
+pub use middleware::{
+    CatchPanic, DecodePayload, ErrorToResponse, Handler, HandlerExt, Layer, LogContext, Panicked,
+    PayloadError, TimedOut, Timeout,
+};
+pub use redact::{set_redactor, Redactor, DEFAULT_REDACTED_FIELDS, REDACTED};
+pub use request_context::{RequestContext, DEBUG_HEADER};
+#[cfg(feature = "runtime")]
+pub use runtime::{
+    run, Runtime, RuntimeError, HANDLER_ERROR, INVALID_EVENT_ERROR, INVALID_RESPONSE_ERROR,
+    RESPONSE_TOO_LARGE_ERROR,
+};
+pub use streaming::{compress_async, compress_to_writer, decompress_async, decompress_from_reader};
+#[cfg(feature = "telemetry")]
+pub use telemetry::{setup_lambda_telemetry, TelemetryBuilder};
+pub use versioned::{NoPrevious, Upcast, VersionError, Versioned};
+
+/// Helper macro until the Try block syntax gets stable https://github.com/rust-lang/rust/issues/31436
+#[macro_export]
+macro_rules! try_block {
+    { $($token:tt)* } => {{
+        let l = || {
+            $($token)*
+        };
+        l()
+    }}
+}
+
+/// Serialized as `gzip(toJson(data))`
+/// Derialized as `gunzip(fromJson(data))`
+///
+/// Deserializing is subject to the process wide [DecompressLimits], see [set_decompress_limits]. By default the
+/// data may decompress to at most [DEFAULT_MAX_DECOMPRESSED_SIZE] (16 MiB) of JSON, nested at most
+/// [DEFAULT_MAX_JSON_DEPTH] (64) arrays and objects deep, and fails with [DecompressError::TooLarge] otherwise.
+#[derive(Clone, Debug)]
+pub struct GzippedJSON<T>(pub T);
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for GzippedJSON<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = <serde_bytes::ByteBuf>::deserialize(deserializer)?;
+        Ok(GzippedJSON(
+            decompress(&bytes).map_err(serde::de::Error::custom)?,
+        ))
+    }
+}
+
+impl<T: Serialize> Serialize for GzippedJSON<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let bytes = compress(&self.0).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CompressError {
+    #[error("GZ encoder error: {0}")]
+    EncoderWriterError(#[from] std::io::Error),
+    #[error("bad json response. Error: {0}")]
+    UnexpectedJsonResponse(#[from] serde_json::Error),
+}
+
+/// Gzip compress that is typically used together with base64 encoding to minimize data sent/stored
+pub fn compress<T: Serialize>(input: T) -> Result<Vec<u8>, CompressError> {
+    let mut e = GzEncoder::new(Vec::new(), Compression::default());
+    e.write_all(&serde_json::to_vec(&input)?)?;
+    Ok(e.finish()?)
+}
+
+/// JSON below this many bytes is stored raw by [compress_with_threshold], as gzip would not make it smaller.
+pub const DEFAULT_COMPRESS_THRESHOLD: usize = 256;
+
+/// Marks data written by [compress_with_threshold] that is stored raw.
+const RAW_FRAME: u8 = 0x00;
+/// Marks data written by [compress_with_threshold] that is gzipped.
+const GZIP_FRAME: u8 = 0x01;
+
+/// Like [compress], but only gzips JSON of at least `threshold` bytes, and prefixes the data with a one byte marker
+/// of whether it is raw or gzipped.
+///
+/// [decompress] reads both this framing and plain gzip, so it can be adopted for data that is already stored.
+pub fn compress_with_threshold<T: Serialize>(
+    input: T,
+    threshold: usize,
+) -> Result<Vec<u8>, CompressError> {
+    Ok(frame(&serde_json::to_vec(&input)?, threshold)?)
+}
+
+fn frame(data: &[u8], threshold: usize) -> std::io::Result<Vec<u8>> {
+    if data.len() < threshold {
+        let mut output = Vec::with_capacity(data.len() + 1);
+        output.push(RAW_FRAME);
+        output.extend_from_slice(data);
+        return Ok(output);
+    }
+    let mut e = GzEncoder::new(vec![GZIP_FRAME], Compression::default());
+    e.write_all(data)?;
+    e.finish()
+}
+
+/// A reader of the uncompressed data, for data framed by [compress_with_threshold] as well as plain gzip.
+fn framed_decoder<'a, R: BufRead + 'a>(mut reader: R) -> std::io::Result<Box<dyn Read + 'a>> {
+    match reader.fill_buf()?.first() {
+        Some(&RAW_FRAME) => {
+            reader.consume(1);
+            Ok(Box::new(reader))
+        }
+        Some(&GZIP_FRAME) => {
+            reader.consume(1);
+            Ok(Box::new(GzDecoder::new(reader)))
+        }
+        _ => Ok(Box::new(GzDecoder::new(reader))),
+    }
+}
+
+fn is_framed(input: &[u8]) -> bool {
+    matches!(input.first(), Some(&RAW_FRAME | &GZIP_FRAME))
+}
+
+#[derive(Error, Debug)]
+pub enum DecompressError {
+    #[error("GZ decoder error: {0}")]
+    DecoderWriterError(#[from] std::io::Error),
+    #[error("bad json response. Error: {0}")]
+    UnexpectedJsonResponse(#[from] serde_json::Error),
+    #[error("decompressed data is too large: {0}")]
+    TooLarge(#[from] LimitExceeded),
+}
+
+impl From<limits::LimitError> for DecompressError {
+    fn from(e: limits::LimitError) -> Self {
+        match e {
+            limits::LimitError::Io(e) => DecompressError::DecoderWriterError(e),
+            limits::LimitError::Exceeded(exceeded) => DecompressError::TooLarge(exceeded),
+        }
+    }
+}
+
+/// Gzip decompress that is typically used together with base64 encoding to minimize data sent/stored
+///
+/// `input` must be gzip bytes, optionally framed by [compress_with_threshold], see [decompress_any] for data that
+/// may have been stored in other ways.
+/// Fails with [DecompressError::TooLarge] if the data exceeds the [DecompressLimits] set for the process.
+pub fn decompress<T: DeserializeOwned>(input: &[u8]) -> Result<T, DecompressError> {
+    decompress_with_limits(input, &decompress_limits())
+}
+
+/// [decompress] with other limits than the process wide [DecompressLimits].
+pub fn decompress_with_limits<T: DeserializeOwned>(
+    input: &[u8],
+    limits: &DecompressLimits,
+) -> Result<T, DecompressError> {
+    log::trace!("About to decompress {} bytes", input.len());
+
+    // The input must be the gzip bytes themselves, base64 encoded input is not decoded here.
+    // Use `decompress_any` for data that may also be base64 encoded or plain JSON.
+    let json = limits.read_to_end(framed_decoder(input)?)?;
+    from_json(&json, limits)
+}
+
+fn from_json<T: DeserializeOwned>(
+    json: &[u8],
+    limits: &DecompressLimits,
+) -> Result<T, DecompressError> {
+    limits.check_json_depth(json)?;
+    Ok(serde_json::from_slice(json)?)
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Decompress data written by any of our services over time, which is one of
+/// - gzip bytes, as written by [compress] and [GzippedJSON], optionally framed by [compress_with_threshold]
+/// - base64 encoded gzip bytes, optionally quoted as a JSON string
+/// - base64 encoded JSON object or array
+/// - plain JSON
+pub fn decompress_any<T: DeserializeOwned>(input: &[u8]) -> Result<T, DecompressError> {
+    if input.starts_with(&GZIP_MAGIC) || is_framed(input) {
+        return decompress(input);
+    }
+    let trimmed = input.trim_ascii();
+    let unquoted = trimmed
+        .strip_prefix(b"\"")
+        .and_then(|input| input.strip_suffix(b"\""))
+        .unwrap_or(trimmed);
+    if let Ok(decoded) = base64::decode(unquoted) {
+        if decoded.starts_with(&GZIP_MAGIC) {
+            return decompress(&decoded);
+        }
+        // Unlike the gzip magic, a single marker byte may well be the start of a base64 encoded string
+        if is_framed(&decoded) {
+            if let Ok(value) = decompress(&decoded) {
+                return Ok(value);
+            }
+        }
+        if decoded.trim_ascii_start().starts_with(b"{")
+            || decoded.trim_ascii_start().starts_with(b"[")
+        {
+            if let Ok(value) = from_json(&decoded, &decompress_limits()) {
+                return Ok(value);
+            }
+        }
+    }
+    from_json(input, &decompress_limits())
+}
+
+impl<T: DeserializeOwned> GzippedJSON<T> {
+    /// Deserialize data in any of the forms accepted by [decompress_any], including a JSON object stored as is,
+    /// so records written before a field was compressed can still be read.
+    ///
+    /// ```ignore
+    /// #[derive(Deserialize)]
+    /// struct Item {
+    ///     #[serde(deserialize_with = "GzippedJSON::deserialize_lenient")]
+    ///     data: GzippedJSON<Data>,
+    /// }
+    /// ```
+    pub fn deserialize_lenient<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer
+            .deserialize_any(LenientVisitor(std::marker::PhantomData))
+            .map(GzippedJSON)
+    }
+}
+
+struct LenientVisitor<T>(std::marker::PhantomData<T>);
+
+/// The bytes of an array that only holds numbers in the range of a byte.
+fn byte_values(value: &serde_json::Value) -> Option<Vec<u8>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|byte| byte.as_u64()?.try_into().ok())
+        .collect()
+}
+
+impl<'de, T: DeserializeOwned> serde::de::Visitor<'de> for LenientVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("gzip bytes, base64 text or JSON")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        decompress_any(v).map_err(E::custom)
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        decompress_any(v.as_bytes()).map_err(E::custom)
+    }
+
+    /// A legacy JSON array, or [GzippedJSON] itself in formats without a bytes type (e.g. JSON), where it is
+    /// serialized as an array of byte values.
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        use serde::de::Error;
+
+        let value =
+            serde_json::Value::deserialize(serde::de::value::SeqAccessDeserializer::new(seq))?;
+        if let Some(bytes) = byte_values(&value) {
+            if bytes.starts_with(&GZIP_MAGIC) {
+                return decompress(&bytes).map_err(A::Error::custom);
+            }
+            // Unlike the gzip magic, a single marker byte may well be the start of a plain array of numbers
+            if is_framed(&bytes) {
+                if let Ok(value) = decompress(&bytes) {
+                    return Ok(value);
+                }
+            }
+        }
+        T::deserialize(value).map_err(A::Error::custom)
+    }
+
+    fn visit_bool<E: serde::de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        T::deserialize(serde_json::Value::from(v)).map_err(E::custom)
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        T::deserialize(serde_json::Value::from(v)).map_err(E::custom)
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        T::deserialize(serde_json::Value::from(v)).map_err(E::custom)
+    }
+
+    fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        T::deserialize(serde_json::Value::from(v)).map_err(E::custom)
+    }
+
+    fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+        T::deserialize(serde_json::Value::Null).map_err(E::custom)
+    }
+
+    fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+        self.visit_unit()
+    }
+
+    fn visit_some<D: serde::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        T::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::model::AttributeValue;
+    use serde_dynamo::{from_attribute_value, to_attribute_value};
+
+    use serde::Deserialize;
+
+    use crate::misc::compress;
+
+    use super::{
+        compress_with_threshold, decompress, decompress_any, decompress_from_reader,
+        decompress_with_limits, frame, DecompressError, DecompressLimits, GzippedJSON,
+        LimitExceeded, DEFAULT_COMPRESS_THRESHOLD, DEFAULT_MAX_DECOMPRESSED_SIZE,
+        DEFAULT_MAX_JSON_DEPTH,
+    };
+
+    #[test]
+    fn test_compress_decompress() {
+        let input = "hejhej";
+        let compressed = compress(input).unwrap();
+        let decompressed: String = decompress(&compressed).unwrap();
+        assert_eq!(input, decompressed)
+    }
+
+    #[test]
+    fn test_compress_with_threshold() {
+        let small = compress_with_threshold("hejhej", DEFAULT_COMPRESS_THRESHOLD).unwrap();
+        assert_eq!(small, b"\x00\"hejhej\"");
+        assert!(small.len() < compress("hejhej").unwrap().len());
+        let large = vec!["hejhej"; 100];
+        let compressed = compress_with_threshold(&large, DEFAULT_COMPRESS_THRESHOLD).unwrap();
+        assert_eq!(compressed[0], 0x01);
+
+        for input in [&small, &compressed, &compress(&large).unwrap()] {
+            let back: serde_json::Value = decompress(input).unwrap();
+            let streamed: serde_json::Value = decompress_from_reader(input.as_slice()).unwrap();
+            assert_eq!(back, streamed);
+            assert_eq!(decompress_any::<serde_json::Value>(input).unwrap(), back);
+            let base64 = base64::encode(input);
+            assert_eq!(
+                decompress_any::<serde_json::Value>(base64.as_bytes()).unwrap(),
+                back
+            );
+        }
+        // A base64 encoded string that decodes to a marker byte is not mistaken for framed data
+        assert_eq!(decompress_any::<String>(br#""AAAA""#).unwrap(), "AAAA");
+    }
+
+    #[test]
+    fn test_decompress() {
+        let input = [
+            72, 52, 115, 73, 65, 65, 65, 65, 65, 65, 65, 65, 65, 52, 117, 117, 86, 107, 112, 74,
+            76, 69, 108, 85, 115, 113, 112, 87, 75, 105, 48, 65, 115, 108, 73, 100, 99, 120, 75,
+            76, 99, 107, 72, 99, 122, 66, 81, 108, 75, 121, 85, 76, 115, 120, 81, 68, 83, 49, 78,
+            106, 65, 49, 50, 68, 82, 70, 77, 84, 88, 85, 80, 68, 49, 71, 84, 100, 74, 68, 80, 76,
+            78, 78, 51, 107, 78, 75, 79, 48, 112, 66, 82, 106, 111, 53, 82, 85, 99, 119, 79, 108,
+            50, 116, 114, 97, 87, 65, 65, 57, 116, 83, 56, 84, 83, 65, 65, 65, 65, 65, 61, 61,
+        ];
+        let x: serde_json::Value = decompress(&base64::decode(input).unwrap()).unwrap();
+        let id = x.as_array().unwrap()[0]
+            .get("data")
+            .unwrap()
+            .get("updateAlarm")
+            .unwrap()
+            .get("id")
+            .unwrap()
+            .as_str()
+            .unwrap();
+        assert_eq!(id, "86d09530-0a54-11ec-b69f-cf2fbd32de70")
+    }
+
+    #[test]
+    fn test_gzip_wrapper() {
+        let gzipped = GzippedJSON("hej".to_string());
+        let attr_value: AttributeValue = to_attribute_value(gzipped.clone()).unwrap();
+        assert!(attr_value.is_b());
+        let back: GzippedJSON<String> = from_attribute_value(attr_value).unwrap();
+        assert_eq!(gzipped.0, back.0)
+    }
+
+    #[test]
+    fn test_decompress_any() {
+        let json = br#"{"id":"abc","values":[1,2]}"#;
+        let expected: serde_json::Value = serde_json::from_slice(json).unwrap();
+        let gzipped = compress(&expected).unwrap();
+        let base64 = base64::encode(&gzipped);
+        for input in [
+            gzipped.clone(),
+            base64.clone().into_bytes(),
+            format!("\"{}\"\n", base64).into_bytes(),
+            base64::encode(json).into_bytes(),
+            json.to_vec(),
+        ] {
+            assert_eq!(
+                decompress_any::<serde_json::Value>(&input).unwrap(),
+                expected
+            );
+        }
+        // Short JSON values can also be valid base64
+        assert_eq!(decompress_any::<u32>(b"1234").unwrap(), 1234);
+        assert!(decompress_any::<serde_json::Value>(b"not json").is_err());
+    }
+
+    #[test]
+    fn test_lenient_gzip_wrapper() {
+        #[derive(Deserialize)]
+        struct Item {
+            #[serde(deserialize_with = "GzippedJSON::deserialize_lenient")]
+            data: GzippedJSON<Vec<String>>,
+        }
+        let data = vec!["hej".to_string()];
+        let gzipped = serde_json::to_string(&GzippedJSON(data.clone())).unwrap();
+        let base64 = base64::encode(compress(&data).unwrap());
+        for item in [
+            format!(r#"{{"data": {}}}"#, gzipped),
+            format!(r#"{{"data": "{}"}}"#, base64),
+            r#"{"data": "[\"hej\"]"}"#.to_string(),
+        ] {
+            let item: Item = serde_json::from_str(&item).unwrap();
+            assert_eq!(item.data.0, data);
+        }
+
+        #[derive(Deserialize)]
+        struct Object {
+            #[serde(deserialize_with = "GzippedJSON::deserialize_lenient")]
+            data: GzippedJSON<serde_json::Value>,
+        }
+        let item: Object = serde_json::from_str(r#"{"data": {"id": "abc"}}"#).unwrap();
+        assert_eq!(item.data.0["id"], "abc");
+
+        #[derive(Deserialize)]
+        struct Attribute(
+            #[serde(deserialize_with = "GzippedJSON::deserialize_lenient")]
+            GzippedJSON<Vec<String>>,
+        );
+        let attr_value: AttributeValue = to_attribute_value(GzippedJSON(data.clone())).unwrap();
+        let back: Attribute = from_attribute_value(attr_value).unwrap();
+        assert_eq!(back.0 .0, data);
+        let back: Attribute = from_attribute_value(AttributeValue::S(base64)).unwrap();
+        assert_eq!(back.0 .0, data);
+    }
+
+    #[test]
+    fn test_lenient_legacy_json() {
+        #[derive(Deserialize)]
+        struct Item<T: serde::de::DeserializeOwned> {
+            #[serde(deserialize_with = "GzippedJSON::deserialize_lenient")]
+            data: GzippedJSON<T>,
+        }
+        let item: Item<Vec<String>> = serde_json::from_str(r#"{"data": ["hej"]}"#).unwrap();
+        assert_eq!(item.data.0, vec!["hej".to_string()]);
+        // Arrays of numbers are only read as bytes when they are compressed data
+        let item: Item<Vec<u8>> = serde_json::from_str(r#"{"data": [1, 2, 3]}"#).unwrap();
+        assert_eq!(item.data.0, vec![1, 2, 3]);
+        let item: Item<u32> = serde_json::from_str(r#"{"data": 42}"#).unwrap();
+        assert_eq!(item.data.0, 42);
+        let item: Item<f64> = serde_json::from_str(r#"{"data": 1.5}"#).unwrap();
+        assert_eq!(item.data.0, 1.5);
+        let item: Item<bool> = serde_json::from_str(r#"{"data": true}"#).unwrap();
+        assert!(item.data.0);
+        let item: Item<Option<u32>> = serde_json::from_str(r#"{"data": null}"#).unwrap();
+        assert_eq!(item.data.0, None);
+
+        #[derive(Deserialize)]
+        struct Attribute<T: serde::de::DeserializeOwned>(
+            #[serde(deserialize_with = "GzippedJSON::deserialize_lenient")] GzippedJSON<T>,
+        );
+        let list = AttributeValue::L(vec![AttributeValue::M(
+            [("id".to_string(), AttributeValue::S("abc".to_string()))].into(),
+        )]);
+        let back: Attribute<serde_json::Value> = from_attribute_value(list).unwrap();
+        assert_eq!(back.0 .0, serde_json::json!([{"id": "abc"}]));
+        let back: Attribute<u32> =
+            from_attribute_value(AttributeValue::N("42".to_string())).unwrap();
+        assert_eq!(back.0 .0, 42);
+    }
+
+    #[test]
+    fn test_decompress_limits() {
+        // 10 MB of zeros compress to about 10 kB
+        let bomb = compress(vec![0u8; 10_000_000]).unwrap();
+        assert!(bomb.len() < 100_000);
+        let limits = DecompressLimits::default().max_size(1_000_000);
+        assert!(matches!(
+            decompress_with_limits::<Vec<u8>>(&bomb, &limits),
+            Err(DecompressError::TooLarge(LimitExceeded::Size(1_000_000)))
+        ));
+        let small = compress(vec![0u8; 10]).unwrap();
+        assert_eq!(
+            decompress_with_limits::<Vec<u8>>(&small, &limits).unwrap(),
+            vec![0u8; 10]
+        );
+
+        let nested = compress(serde_json::json!([[[[1]]]])).unwrap();
+        assert!(decompress_with_limits::<serde_json::Value>(&nested, &limits).is_ok());
+        assert!(matches!(
+            decompress_with_limits::<serde_json::Value>(&nested, &limits.max_depth(3)),
+            Err(DecompressError::TooLarge(LimitExceeded::Depth(3)))
+        ));
+    }
+
+    #[test]
+    fn test_default_decompress_limits() {
+        let limits = DecompressLimits::default();
+        let bomb = compress(vec![0u8; DEFAULT_MAX_DECOMPRESSED_SIZE / 2]).unwrap();
+        assert!(matches!(
+            decompress_with_limits::<Vec<u8>>(&bomb, &limits),
+            Err(DecompressError::TooLarge(LimitExceeded::Size(
+                DEFAULT_MAX_DECOMPRESSED_SIZE
+            )))
+        ));
+
+        let nested = |depth: usize| {
+            frame(
+                format!("{}{}", "[".repeat(depth), "]".repeat(depth)).as_bytes(),
+                0,
+            )
+            .unwrap()
+        };
+        assert!(decompress_with_limits::<serde_json::Value>(
+            &nested(DEFAULT_MAX_JSON_DEPTH),
+            &limits
+        )
+        .is_ok());
+        // Deep enough for serde_json to give up, but reported as exceeding our limit first
+        let deeper = nested(200);
+        assert!(matches!(
+            decompress_with_limits::<serde_json::Value>(&deeper, &limits),
+            Err(DecompressError::TooLarge(LimitExceeded::Depth(
+                DEFAULT_MAX_JSON_DEPTH
+            )))
+        ));
+        assert!(matches!(
+            decompress_with_limits::<serde_json::Value>(&deeper, &limits.unlimited_depth()),
+            Err(DecompressError::UnexpectedJsonResponse(_))
+        ));
+    }
+}
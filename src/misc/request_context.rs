@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::filter::DebugRequest;
+
+tokio::task_local! {
+    static REQUEST_CONTEXT: RequestContext;
+}
+
+/// Header a caller can set (to any value) to have DEBUG logs written for its request.
+///
+/// Lambda callers set it in the `custom` map of the client context, which [super::Runtime] reads and which is
+/// forwarded to the lambdas invoked on behalf of the request, see [RequestContext::client_context]. HTTP handlers
+/// read it from the request headers in a [super::LogContext]:
+///
+/// ```ignore
+/// handler.with(LogContext::new(|request: &Request, context: RequestContext| {
+///     context.with_debug(request.headers.contains_key(DEBUG_HEADER))
+/// }))
+/// ```
+pub const DEBUG_HEADER: &str = "x-debug-log";
+
+static COLD_START: AtomicBool = AtomicBool::new(true);
+
+/// Key of the trace id in the `custom` map of a Lambda client context.
+const TRACE_ID_KEY: &str = "traceId";
+
+/// The [client context](https://docs.aws.amazon.com/lambda/latest/dg/API_Invoke.html#API_Invoke_RequestSyntax)
+/// passed to invoked lambdas, of which only the string map `custom` is used.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ClientContext {
+    #[serde(default)]
+    custom: HashMap<String, String>,
+}
+
+/// Information about the request currently being handled, available to everything running in its scope.
+///
+/// The context is stored in a tokio task-local, so concurrent requests handled by the same process
+/// (e.g. in an ECS service) each see their own context.
+///
+/// ```ignore
+/// RequestContext::new(request_id)
+///     .with_trace_id(trace_id)
+///     .scope(async move { handle(event).await })
+///     .await
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequestContext {
+    request_id: String,
+    trace_id: Option<String>,
+    user_sub: Option<String>,
+    cold_start: bool,
+    debug: bool,
+    deadline: Option<SystemTime>,
+}
+
+impl RequestContext {
+    /// The first context created in the process is marked as a cold start.
+    pub fn new(request_id: String) -> Self {
+        RequestContext {
+            request_id,
+            trace_id: None,
+            user_sub: None,
+            cold_start: COLD_START.swap(false, Ordering::Relaxed),
+            debug: false,
+            deadline: None,
+        }
+    }
+
+    /// A context that does not take part in the cold start tracking.
+    pub(crate) fn untracked(request_id: String) -> Self {
+        RequestContext {
+            request_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_trace_id(mut self, trace_id: String) -> Self {
+        self.trace_id = Some(trace_id);
+        self
+    }
+
+    pub fn with_user_sub(mut self, user_sub: String) -> Self {
+        self.user_sub = Some(user_sub);
+        self
+    }
+
+    /// Enable DEBUG logs for this request regardless of the [super::LogFilter], e.g. when the caller sent the
+    /// [DEBUG_HEADER] header.
+    pub fn with_debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// When the invocation times out.
+    pub fn with_deadline(mut self, deadline: SystemTime) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_deref()
+    }
+
+    pub fn user_sub(&self) -> Option<&str> {
+        self.user_sub.as_deref()
+    }
+
+    pub fn cold_start(&self) -> bool {
+        self.cold_start
+    }
+
+    pub fn debug(&self) -> bool {
+        self.debug
+    }
+
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+    }
+
+    /// Run `f` with this context as the current context.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        let _debug = self.debug.then(DebugRequest::start);
+        REQUEST_CONTEXT.scope(self, f).await
+    }
+
+    /// Run the synchronous `f` with this context as the current context.
+    pub fn sync_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        let _debug = self.debug.then(DebugRequest::start);
+        REQUEST_CONTEXT.sync_scope(self, f)
+    }
+
+    /// Call `f` with the current context, or `None` if not called within the scope of a context.
+    pub fn with_current<R>(f: impl FnOnce(Option<&RequestContext>) -> R) -> R {
+        let mut f = Some(f);
+        REQUEST_CONTEXT
+            .try_with(|context| (f.take().unwrap())(Some(context)))
+            .unwrap_or_else(|_| (f.take().unwrap())(None))
+    }
+
+    /// A clone of the current context, if any.
+    pub fn current() -> Option<RequestContext> {
+        Self::with_current(|context| context.cloned())
+    }
+
+    /// The JSON client context to invoke other lambdas with on behalf of this request, so they continue its trace
+    /// and write DEBUG logs if this request does. Lambda expects it base64 encoded.
+    pub fn client_context(&self) -> Option<String> {
+        let mut custom = HashMap::new();
+        if let Some(trace_id) = &self.trace_id {
+            custom.insert(TRACE_ID_KEY.to_string(), trace_id.clone());
+        }
+        if self.debug {
+            custom.insert(DEBUG_HEADER.to_string(), "true".to_string());
+        }
+        if custom.is_empty() {
+            return None;
+        }
+        serde_json::to_string(&ClientContext { custom }).ok()
+    }
+
+    /// Continue the trace of the caller that invoked the lambda with a [RequestContext::client_context], and
+    /// enable DEBUG logs if the caller set [DEBUG_HEADER]. Client contexts that are not JSON are ignored.
+    pub fn with_client_context(mut self, client_context: &str) -> Self {
+        let ClientContext { mut custom } = serde_json::from_str(client_context).unwrap_or_default();
+        if let Some(trace_id) = custom.remove(TRACE_ID_KEY) {
+            self.trace_id = Some(trace_id);
+        }
+        self.debug |= custom.contains_key(DEBUG_HEADER);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestContext;
+
+    #[tokio::test]
+    async fn concurrent_scopes_are_isolated() {
+        let request = |id: &'static str| {
+            RequestContext::new(id.to_string()).scope(async move {
+                tokio::task::yield_now().await;
+                RequestContext::with_current(|context| context.unwrap().request_id().to_string())
+            })
+        };
+        let (a, b) = tokio::join!(request("a"), request("b"));
+        assert_eq!((a.as_str(), b.as_str()), ("a", "b"));
+        assert_eq!(RequestContext::current(), None);
+    }
+
+    #[test]
+    fn only_first_context_is_cold_start() {
+        let first = RequestContext::new("1".to_string());
+        let second = RequestContext::new("2".to_string());
+        // Other tests may have created the first context of the process
+        assert!(!second.cold_start());
+        let current = first
+            .clone()
+            .with_trace_id("trace".to_string())
+            .sync_scope(RequestContext::current)
+            .unwrap();
+        assert_eq!(current.trace_id(), Some("trace"));
+        assert_eq!(current.cold_start(), first.cold_start());
+    }
+
+    #[test]
+    fn propagate_through_client_context() {
+        let caller = RequestContext::untracked("1".to_string());
+        assert_eq!(caller.client_context(), None);
+        let caller = caller.with_trace_id("Root=1-5e1b4151".to_string());
+        let client_context = caller.client_context().unwrap();
+
+        let invoked = RequestContext::untracked("2".to_string())
+            .with_trace_id("Root=1-own".to_string())
+            .with_client_context(&client_context);
+        assert_eq!(invoked.trace_id(), Some("Root=1-5e1b4151"));
+        assert_eq!(invoked.request_id(), "2");
+
+        let invoked = RequestContext::untracked("3".to_string())
+            .with_trace_id("Root=1-own".to_string())
+            .with_client_context(r#"{"client": {"installation_id": "app"}}"#)
+            .with_client_context("not json");
+        assert_eq!(invoked.trace_id(), Some("Root=1-own"));
+        assert!(!invoked.debug());
+    }
+
+    #[test]
+    fn force_debug_through_client_context() {
+        let invoked = RequestContext::untracked("1".to_string())
+            .with_client_context(r#"{"custom": {"x-debug-log": "1"}}"#);
+        assert!(invoked.debug());
+
+        let forwarded = RequestContext::untracked("2".to_string())
+            .with_client_context(&invoked.client_context().unwrap());
+        assert!(forwarded.debug());
+    }
+}
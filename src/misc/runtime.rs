@@ -0,0 +1,416 @@
+use std::{
+    fmt::Display,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use hyper::{
+    body::to_bytes, client::HttpConnector, header::HeaderMap, Body, Client, Method, Request,
+    Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use super::{logging::AWS_LAMBDA_RUNTIME_API, Handler, RequestContext};
+
+const RUNTIME_API_VERSION: &str = "2018-06-01";
+const REQUEST_ID_HEADER: &str = "lambda-runtime-aws-request-id";
+const DEADLINE_HEADER: &str = "lambda-runtime-deadline-ms";
+const TRACE_ID_HEADER: &str = "lambda-runtime-trace-id";
+const CLIENT_CONTEXT_HEADER: &str = "lambda-runtime-client-context";
+const ERROR_TYPE_HEADER: &str = "lambda-runtime-function-error-type";
+
+/// `errorType` of an invocation whose handler returned an error.
+pub const HANDLER_ERROR: &str = "HandlerError";
+/// `errorType` of an invocation whose event could not be deserialized.
+pub const INVALID_EVENT_ERROR: &str = "InvalidEvent";
+/// `errorType` of an invocation whose response could not be serialized.
+pub const INVALID_RESPONSE_ERROR: &str = "InvalidResponse";
+/// `errorType` of an invocation whose response was rejected by the runtime API for exceeding the payload limit.
+pub const RESPONSE_TOO_LARGE_ERROR: &str = "ResponseTooLarge";
+
+#[derive(Error, Debug)]
+pub enum RuntimeError {
+    #[error("AWS_LAMBDA_RUNTIME_API is not set")]
+    MissingEndpoint,
+    #[error("runtime api request failed: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("bad runtime api request: {0}")]
+    Request(#[from] hyper::http::Error),
+    #[error("runtime api responded with status {0}")]
+    Status(hyper::StatusCode),
+    #[error("invocation is missing the `{0}` header")]
+    MissingHeader(&'static str),
+    #[error("bad json in invocation response. Error: {0}")]
+    UnexpectedJson(#[from] serde_json::Error),
+}
+
+/// The body posted to the runtime API when an invocation fails.
+#[derive(Serialize, Debug)]
+struct ErrorResponse {
+    #[serde(rename = "errorType")]
+    error_type: &'static str,
+    #[serde(rename = "errorMessage")]
+    error_message: String,
+}
+
+impl ErrorResponse {
+    fn new(error_type: &'static str, error: &impl Display) -> Self {
+        ErrorResponse {
+            error_type,
+            error_message: error.to_string(),
+        }
+    }
+}
+
+/// A client of the [Lambda Runtime API](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html), which
+/// fetches the invocations of the function, hands them to a [Handler] and reports the results.
+///
+/// Each invocation is handled in the scope of a [RequestContext], so its logs are prefixed with its request id.
+///
+/// ```ignore
+/// bb_rust::misc::run(|event: MyEvent| async move { handle(event).await }).await?;
+/// ```
+pub struct Runtime {
+    client: Client<HttpConnector>,
+    endpoint: String,
+}
+
+impl Runtime {
+    /// `endpoint` is the `host:port` of the runtime API.
+    pub fn new(endpoint: String) -> Self {
+        Runtime {
+            client: Client::new(),
+            endpoint,
+        }
+    }
+
+    /// Use the endpoint Lambda passes through `AWS_LAMBDA_RUNTIME_API`.
+    pub fn from_env() -> Result<Self, RuntimeError> {
+        AWS_LAMBDA_RUNTIME_API
+            .clone()
+            .map(Runtime::new)
+            .ok_or(RuntimeError::MissingEndpoint)
+    }
+
+    /// Handle invocations until fetching the next invocation fails.
+    pub async fn run<T, H>(&self, handler: H) -> Result<(), RuntimeError>
+    where
+        T: DeserializeOwned,
+        H: Handler<T>,
+        H::Response: Serialize,
+        H::Error: Display,
+    {
+        loop {
+            self.process_next(&handler).await?;
+        }
+    }
+
+    /// Fetch the next invocation, handle it and report the result.
+    ///
+    /// Handler errors and events that cannot be deserialized into `T` are reported to the runtime API as
+    /// invocation errors. Failures to post the result only affect this invocation, so they are logged; only
+    /// failures to fetch the invocation are returned.
+    pub async fn process_next<T, H>(&self, handler: &H) -> Result<(), RuntimeError>
+    where
+        T: DeserializeOwned,
+        H: Handler<T>,
+        H::Response: Serialize,
+        H::Error: Display,
+    {
+        let response = self
+            .send(Method::GET, "invocation/next", None, Body::empty())
+            .await?;
+        let context = invocation_context(response.headers())?;
+        let request_id = context.request_id().to_string();
+        let event = to_bytes(response.into_body()).await?;
+
+        let result = context
+            .scope(async {
+                let result = match serde_json::from_slice::<T>(&event) {
+                    Ok(event) => match handler.call(event).await {
+                        Ok(response) => serde_json::to_vec(&response)
+                            .map_err(|e| ErrorResponse::new(INVALID_RESPONSE_ERROR, &e)),
+                        Err(e) => Err(ErrorResponse::new(HANDLER_ERROR, &e)),
+                    },
+                    Err(e) => Err(ErrorResponse::new(INVALID_EVENT_ERROR, &e)),
+                };
+                if let Err(error) = &result {
+                    log::error!("Invocation failed: {}", error.error_message);
+                }
+                result
+            })
+            .await;
+
+        self.post_result(&request_id, result).await;
+        Ok(())
+    }
+
+    async fn post_result(&self, request_id: &str, result: Result<Vec<u8>, ErrorResponse>) {
+        let error = match result {
+            Ok(response) => {
+                let path = format!("invocation/{}/response", request_id);
+                match self
+                    .send(Method::POST, &path, None, Body::from(response))
+                    .await
+                {
+                    Ok(_) => return,
+                    Err(RuntimeError::Status(StatusCode::PAYLOAD_TOO_LARGE)) => ErrorResponse::new(
+                        RESPONSE_TOO_LARGE_ERROR,
+                        &"response exceeds the Lambda payload limit",
+                    ),
+                    Err(e) => {
+                        log::error!("Failed posting response of {}: {}", request_id, e);
+                        return;
+                    }
+                }
+            }
+            Err(error) => error,
+        };
+        let path = format!("invocation/{}/error", request_id);
+        let body = serde_json::to_vec(&error).expect("error response is serializable");
+        if let Err(e) = self
+            .send(
+                Method::POST,
+                &path,
+                Some(error.error_type),
+                Body::from(body),
+            )
+            .await
+        {
+            log::error!("Failed posting error of {}: {}", request_id, e);
+        }
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        error_type: Option<&str>,
+        body: Body,
+    ) -> Result<Response<Body>, RuntimeError> {
+        let mut request = Request::builder().method(method).uri(format!(
+            "http://{}/{}/runtime/{}",
+            self.endpoint, RUNTIME_API_VERSION, path
+        ));
+        if let Some(error_type) = error_type {
+            request = request.header(ERROR_TYPE_HEADER, error_type);
+        }
+        let response = self.client.request(request.body(body)?).await?;
+        if !response.status().is_success() {
+            return Err(RuntimeError::Status(response.status()));
+        }
+        Ok(response)
+    }
+}
+
+/// Handle invocations with the runtime API Lambda passes through `AWS_LAMBDA_RUNTIME_API`.
+pub async fn run<T, H>(handler: H) -> Result<(), RuntimeError>
+where
+    T: DeserializeOwned,
+    H: Handler<T>,
+    H::Response: Serialize,
+    H::Error: Display,
+{
+    Runtime::from_env()?.run(handler).await
+}
+
+fn invocation_context(headers: &HeaderMap) -> Result<RequestContext, RuntimeError> {
+    let header = |name: &'static str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let request_id =
+        header(REQUEST_ID_HEADER).ok_or(RuntimeError::MissingHeader(REQUEST_ID_HEADER))?;
+    let mut context = RequestContext::new(request_id.to_string());
+    if let Some(trace_id) = header(TRACE_ID_HEADER) {
+        context = context.with_trace_id(trace_id.to_string());
+    }
+    // A trace propagated by the caller takes precedence over the trace Lambda started for the invocation
+    if let Some(client_context) = header(CLIENT_CONTEXT_HEADER) {
+        context = context.with_client_context(client_context);
+    }
+    if let Some(deadline) = header(DEADLINE_HEADER).and_then(|deadline| deadline.parse().ok()) {
+        context = context.with_deadline(UNIX_EPOCH + Duration::from_millis(deadline));
+    }
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use hyper::{
+        body::to_bytes,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use serde::Deserialize;
+
+    use super::{
+        invocation_context, Runtime, CLIENT_CONTEXT_HEADER, DEADLINE_HEADER, ERROR_TYPE_HEADER,
+        REQUEST_ID_HEADER, TRACE_ID_HEADER,
+    };
+    use crate::misc::RequestContext;
+
+    /// A stand-in for the runtime API that serves `event` and records what the runtime posts back, answering
+    /// posted responses with `response_status`.
+    async fn runtime_api(
+        event: &'static str,
+        response_status: u16,
+    ) -> (
+        SocketAddr,
+        Arc<Mutex<Vec<(String, Option<String>, String)>>>,
+    ) {
+        let posted = Arc::new(Mutex::new(Vec::new()));
+        let make_service = {
+            let posted = posted.clone();
+            make_service_fn(move |_| {
+                let posted = posted.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let posted = posted.clone();
+                        async move {
+                            let path = request.uri().path().to_string();
+                            if path.ends_with("/invocation/next") {
+                                return Ok::<_, Infallible>(
+                                    Response::builder()
+                                        .header(REQUEST_ID_HEADER, "req-1")
+                                        .header(TRACE_ID_HEADER, "Root=1-5e1b4151")
+                                        .header(DEADLINE_HEADER, "1700000000000")
+                                        .body(Body::from(event))
+                                        .unwrap(),
+                                );
+                            }
+                            let error_type = request
+                                .headers()
+                                .get(ERROR_TYPE_HEADER)
+                                .map(|value| value.to_str().unwrap().to_string());
+                            let body = to_bytes(request.into_body()).await.unwrap();
+                            let status = if path.ends_with("/response") {
+                                response_status
+                            } else {
+                                202
+                            };
+                            posted.lock().unwrap().push((
+                                path,
+                                error_type,
+                                String::from_utf8(body.to_vec()).unwrap(),
+                            ));
+                            Ok(Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap())
+                        }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, posted)
+    }
+
+    #[derive(Deserialize)]
+    struct Event {
+        name: String,
+    }
+
+    async fn greet(event: Event) -> Result<String, String> {
+        let context = RequestContext::current().unwrap();
+        assert_eq!(context.request_id(), "req-1");
+        assert_eq!(context.trace_id(), Some("Root=1-5e1b4151"));
+        assert_eq!(
+            context.deadline(),
+            Some(UNIX_EPOCH + Duration::from_millis(1700000000000))
+        );
+        match event.name.as_str() {
+            "" => Err("no name".to_string()),
+            name => Ok(format!("Hello {}", name)),
+        }
+    }
+
+    #[tokio::test]
+    async fn post_response() {
+        let (addr, posted) = runtime_api(r#"{"name": "world"}"#, 202).await;
+        let runtime = Runtime::new(addr.to_string());
+        runtime.process_next(&greet).await.unwrap();
+        assert_eq!(
+            posted.lock().unwrap().as_slice(),
+            [(
+                "/2018-06-01/runtime/invocation/req-1/response".to_string(),
+                None,
+                r#""Hello world""#.to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn post_errors() {
+        let (addr, posted) = runtime_api(r#"{"name": ""}"#, 202).await;
+        Runtime::new(addr.to_string())
+            .process_next(&greet)
+            .await
+            .unwrap();
+        let (addr, bad_event_posted) = runtime_api(r#"{"nom": "world"}"#, 202).await;
+        Runtime::new(addr.to_string())
+            .process_next(&greet)
+            .await
+            .unwrap();
+
+        let posted = posted.lock().unwrap();
+        assert_eq!(posted[0].0, "/2018-06-01/runtime/invocation/req-1/error");
+        assert_eq!(posted[0].1.as_deref(), Some("HandlerError"));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&posted[0].2).unwrap(),
+            serde_json::json!({"errorType": "HandlerError", "errorMessage": "no name"})
+        );
+        let posted = bad_event_posted.lock().unwrap();
+        assert_eq!(posted[0].0, "/2018-06-01/runtime/invocation/req-1/error");
+        assert_eq!(posted[0].1.as_deref(), Some("InvalidEvent"));
+    }
+
+    #[tokio::test]
+    async fn report_oversized_response() {
+        let (addr, posted) = runtime_api(r#"{"name": "world"}"#, 413).await;
+        Runtime::new(addr.to_string())
+            .process_next(&greet)
+            .await
+            .unwrap();
+        let posted = posted.lock().unwrap();
+        assert_eq!(posted.len(), 2);
+        assert_eq!(posted[1].0, "/2018-06-01/runtime/invocation/req-1/error");
+        assert_eq!(posted[1].1.as_deref(), Some("ResponseTooLarge"));
+    }
+
+    #[tokio::test]
+    async fn failed_posts_are_not_fatal() {
+        let (addr, posted) = runtime_api(r#"{"name": "world"}"#, 500).await;
+        let runtime = Runtime::new(addr.to_string());
+        runtime.process_next(&greet).await.unwrap();
+        runtime.process_next(&greet).await.unwrap();
+        assert_eq!(posted.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn continue_trace_of_caller() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, "req-1".parse().unwrap());
+        headers.insert(TRACE_ID_HEADER, "Root=1-own".parse().unwrap());
+        assert_eq!(
+            invocation_context(&headers).unwrap().trace_id(),
+            Some("Root=1-own")
+        );
+        headers.insert(
+            CLIENT_CONTEXT_HEADER,
+            r#"{"custom": {"traceId": "Root=1-caller"}}"#.parse().unwrap(),
+        );
+        assert_eq!(
+            invocation_context(&headers).unwrap().trace_id(),
+            Some("Root=1-caller")
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use std::io::{BufReader, ErrorKind, Read, Write};
+
+use flate2::{write::GzEncoder, Compression};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use super::{
+    decompress_limits, framed_decoder, limits::LimitError, CompressError, DecompressError,
+};
+
+/// Like [super::compress], but serializes straight into the gzip encoder writing to `writer`, so the uncompressed
+/// JSON is never held in memory. Returns `writer` once all compressed data is written to it.
+pub fn compress_to_writer<T: Serialize, W: Write>(
+    input: &T,
+    writer: W,
+) -> Result<W, CompressError> {
+    let mut encoder = GzEncoder::new(writer, Compression::default());
+    serde_json::to_writer(&mut encoder, input)?;
+    Ok(encoder.finish()?)
+}
+
+/// Like [super::decompress], but deserializes straight from the gzip decoder reading from `reader`, so the
+/// uncompressed JSON is never held in memory.
+///
+/// Only the size of the [super::DecompressLimits] is applied, as the JSON depth cannot be checked up front.
+pub fn decompress_from_reader<T: DeserializeOwned, R: Read>(
+    reader: R,
+) -> Result<T, DecompressError> {
+    let reader = decompress_limits().limit_reader(framed_decoder(BufReader::new(reader))?);
+    serde_json::from_reader(BufReader::new(reader)).map_err(|e| {
+        if e.is_io() {
+            LimitError::from(std::io::Error::from(e)).into()
+        } else {
+            DecompressError::UnexpectedJsonResponse(e)
+        }
+    })
+}
+
+/// Async version of [compress_to_writer].
+///
+/// Serialization and compression are synchronous, so they run on a blocking thread, which hands the compressed
+/// data to `writer` in chunks as it is produced. Neither the uncompressed JSON nor the compressed data are held in
+/// memory as a whole.
+pub async fn compress_async<T, W>(input: T, writer: &mut W) -> Result<(), CompressError>
+where
+    T: Serialize + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let (sender, mut receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let compressing = tokio::task::spawn_blocking(move || {
+        compress_to_writer(&input, ChannelWriter(sender)).map(drop)
+    });
+    while let Some(chunk) = receiver.recv().await {
+        // Dropping the receiver on error makes the blocking thread stop at its next chunk
+        writer.write_all(&chunk).await?;
+    }
+    join(compressing).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Async version of [decompress_from_reader].
+///
+/// Decompression and deserialization are synchronous, so they run on a blocking thread, which is fed the
+/// compressed data in chunks as it is read from `reader`. Neither the compressed data nor the uncompressed JSON
+/// are held in memory as a whole.
+pub async fn decompress_async<T, R>(reader: &mut R) -> Result<T, DecompressError>
+where
+    T: DeserializeOwned + Send + 'static,
+    R: AsyncRead + Unpin,
+{
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let decompressing = tokio::task::spawn_blocking(move || {
+        decompress_from_reader(ChannelReader {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+        })
+    });
+    loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let chunk = match reader.read(&mut chunk).await {
+            Ok(0) => break,
+            Ok(read) => {
+                chunk.truncate(read);
+                Ok(chunk)
+            }
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        // The blocking thread stops receiving once it is done, e.g. when it hit a limit
+        if sender.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+    drop(sender);
+    join(decompressing).await
+}
+
+/// How many chunks may be in flight between the async and the blocking side.
+const CHANNEL_CAPACITY: usize = 4;
+/// Size of the chunks read by [decompress_async].
+const CHUNK_SIZE: usize = 32 * 1024;
+
+async fn join<T>(task: tokio::task::JoinHandle<T>) -> T {
+    task.await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+/// Sends what is written to it to the async side of [compress_async].
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(buf.to_vec())
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "writer failed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the chunks sent by the async side of [decompress_async].
+struct ChannelReader {
+    receiver: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.chunk.len() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{compress_async, compress_to_writer, decompress_async, decompress_from_reader};
+    use crate::misc::{compress, decompress, DecompressError};
+
+    fn value() -> Value {
+        json!({"id": "86d09530-0a54-11ec-b69f-cf2fbd32de70", "values": vec![1.5; 1000]})
+    }
+
+    #[test]
+    fn compatible_with_compress() {
+        let streamed = compress_to_writer(&value(), Vec::new()).unwrap();
+        assert_eq!(decompress::<Value>(&streamed).unwrap(), value());
+        let compressed = compress(value()).unwrap();
+        assert_eq!(
+            decompress_from_reader::<Value, _>(compressed.as_slice()).unwrap(),
+            value()
+        );
+
+        assert!(matches!(
+            decompress_from_reader::<Value, _>(&compressed[..compressed.len() / 2]),
+            Err(DecompressError::DecoderWriterError(_))
+        ));
+        let null = compress_to_writer(&(), Vec::new()).unwrap();
+        assert!(matches!(
+            decompress_from_reader::<Vec<u8>, _>(null.as_slice()),
+            Err(DecompressError::UnexpectedJsonResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn async_round_trip() {
+        let mut compressed = Vec::new();
+        compress_async(value(), &mut compressed).await.unwrap();
+        assert_eq!(decompress::<Value>(&compressed).unwrap(), value());
+        let back: Value = decompress_async(&mut compressed.as_slice()).await.unwrap();
+        assert_eq!(back, value());
+
+        // Larger than a chunk, to be streamed in several parts
+        let large = json!({"values": (0..100_000).collect::<Vec<_>>()});
+        let mut compressed = Vec::new();
+        compress_async(large.clone(), &mut compressed)
+            .await
+            .unwrap();
+        let mut reader = tokio::io::BufReader::with_capacity(1024, compressed.as_slice());
+        let back: Value = decompress_async(&mut reader).await.unwrap();
+        assert_eq!(back, large);
+    }
+
+    #[tokio::test]
+    async fn async_errors() {
+        let compressed = compress(value()).unwrap();
+        assert!(matches!(
+            decompress_async::<Value, _>(&mut &compressed[..compressed.len() / 2]).await,
+            Err(DecompressError::DecoderWriterError(_))
+        ));
+
+        let (mut writer, reader) = tokio::io::duplex(64);
+        drop(reader);
+        assert!(compress_async(value(), &mut writer).await.is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use std::fmt::{self, Write};
+
+use serde_json::{Map, Value as JsonValue};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_log::{AsLog, NormalizeEvent};
+use tracing_subscriber::{
+    filter,
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        FmtContext, FormatEvent, FormatFields, MakeWriter,
+    },
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
+
+use super::{
+    filter::{env_log_filter_error, log_filter, manage_max_level},
+    logging::{in_lambda, json_line, text_line, LineMetadata},
+    LogFormat, RequestContext, LOG_FILTER_ENV,
+};
+
+/// Sets up a [tracing_subscriber] subscriber with the same layout as [super::LambdaLoggerBuilder].
+///
+/// Every line is prefixed with the request id of the current [RequestContext] and carries the fields of the
+/// spans it is emitted in, e.g. `function_name` and `operation`. Records of the `log` crate are forwarded to
+/// the subscriber, and events are filtered with the current [super::LogFilter].
+pub struct TelemetryBuilder {
+    format: LogFormat,
+}
+
+impl Default for TelemetryBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TelemetryBuilder {
+    pub fn new() -> Self {
+        TelemetryBuilder {
+            format: LogFormat::from_env(),
+        }
+    }
+
+    /// Override the format selected through [super::LOG_FORMAT_ENV].
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Panics if a global subscriber or logger is already set.
+    pub fn init(self) {
+        subscriber(self.format, in_lambda(), std::io::stderr).init();
+        // Records of the `log` crate are forwarded up to `log::max_level`, which follows the filter from now on
+        manage_max_level();
+        if let Some(e) = env_log_filter_error() {
+            tracing::error!("Ignoring {}: {}", LOG_FILTER_ENV, e);
+        }
+    }
+}
+
+/// Set up tracing with the format selected through [super::LOG_FORMAT_ENV].
+pub fn setup_lambda_telemetry() {
+    TelemetryBuilder::new().init();
+}
+
+fn subscriber<W>(format: LogFormat, in_lambda: bool, writer: W) -> impl Subscriber + Send + Sync
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    Registry::default()
+        .with(SpanFieldsLayer)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .event_format(LambdaFormat { format, in_lambda })
+                .with_writer(writer),
+        )
+        .with(filter::dynamic_filter_fn(|metadata, _| {
+            // Spans are always enabled, as their fields are added to every event emitted inside them
+            metadata.is_span() || {
+                let filter = log_filter();
+                RequestContext::with_current(|context| {
+                    filter.enabled(metadata.target(), metadata.level().as_log(), context)
+                })
+            }
+        }))
+}
+
+/// The fields of a span, recorded by [SpanFieldsLayer] so they can be added to the events emitted inside the span.
+struct SpanFields(Map<String, JsonValue>);
+
+struct SpanFieldsLayer;
+
+impl<S> Layer<S> for SpanFieldsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Map::new();
+            attrs.record(&mut JsonFields(&mut fields));
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(&mut JsonFields(fields));
+            }
+        }
+    }
+}
+
+struct LambdaFormat {
+    format: LogFormat,
+    in_lambda: bool,
+}
+
+impl<S, N> FormatEvent<S, N> for LambdaFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        // Events forwarded from the `log` crate carry their real metadata in fields
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        // Fields of inner spans and of the event itself take precedence over those of outer spans
+        let mut fields = Map::new();
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                if let Some(SpanFields(span_fields)) = span.extensions().get::<SpanFields>() {
+                    fields.extend(span_fields.clone());
+                }
+            }
+        }
+        event.record(&mut JsonFields(&mut fields));
+        let message = match fields.remove("message") {
+            Some(JsonValue::String(message)) => message,
+            Some(message) => message.to_string(),
+            None => String::new(),
+        };
+        if !log_filter().message_enabled(&message) {
+            return Ok(());
+        }
+
+        let line = RequestContext::with_current(|context| match self.format {
+            LogFormat::Text => {
+                let mut message = message;
+                for (key, value) in &fields {
+                    match value {
+                        JsonValue::String(value) => write!(message, " {}={}", key, value)?,
+                        value => write!(message, " {}={}", key, value)?,
+                    }
+                }
+                Ok(text_line(
+                    context,
+                    metadata.target(),
+                    metadata.level(),
+                    &message,
+                    self.in_lambda,
+                ))
+            }
+            LogFormat::Json => {
+                let mut timestamp = String::new();
+                SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+                Ok(json_line(
+                    fields,
+                    context,
+                    &LineMetadata {
+                        level: metadata.level().as_str(),
+                        target: metadata.target(),
+                        module_path: metadata.module_path(),
+                        file: metadata.file(),
+                        line: metadata.line(),
+                    },
+                    &message,
+                    timestamp,
+                ))
+            }
+        })?;
+        writeln!(writer, "{}", line)
+    }
+}
+
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl JsonFields<'_> {
+    fn insert(&mut self, field: &Field, value: JsonValue) {
+        // Metadata of events forwarded from the `log` crate, already read through `normalized_metadata`
+        if !field.name().starts_with("log.") {
+            self.0.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for JsonFields<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::subscriber;
+    use crate::misc::{LogFormat, RequestContext};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn emit(format: LogFormat) -> String {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = subscriber(format, true, move || writer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("handler", function_name = "my-function");
+            let _handler = span.enter();
+            let span = tracing::info_span!("query", operation = tracing::field::Empty);
+            let _query = span.enter();
+            span.record("operation", "test");
+            // Filtered by the default filter, unless debug is enabled for the request
+            tracing::info!(target: "my_target", "dropped");
+            RequestContext::new("req".to_string())
+                .with_trace_id("trace".to_string())
+                .with_debug(true)
+                .sync_scope(|| tracing::info!(target: "my_target", count = 3, "first\nsecond"));
+        });
+        let output = output.0.lock().unwrap();
+        String::from_utf8(output.clone()).unwrap()
+    }
+
+    #[test]
+    fn text_format() {
+        assert_eq!(
+            emit(LogFormat::Text),
+            "req\tmy_target\tINFO\tfirst\rsecond count=3 function_name=my-function operation=test\n"
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let output = emit(LogFormat::Json);
+        assert_eq!(output.lines().count(), 1);
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert!(json["timestamp"].is_string());
+        assert!(json["coldStart"].is_boolean());
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["target"], "my_target");
+        assert_eq!(json["requestId"], "req");
+        assert_eq!(json["traceId"], "trace");
+        assert_eq!(json["file"], file!());
+        assert_eq!(json["message"], "first\nsecond");
+        assert_eq!(json["count"], 3);
+        assert_eq!(json["function_name"], "my-function");
+        assert_eq!(json["operation"], "test");
+    }
+}
@@ -0,0 +1,295 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum VersionError {
+    #[error("schema version {version} is not supported, the current version is {current}")]
+    Unsupported { version: u32, current: u32 },
+    #[error("no upcast path from schema version {from}, it predates the oldest supported version")]
+    NoUpcastPath { from: u32 },
+    #[error("bad json for schema version {version}. Error: {source}")]
+    UnexpectedJson {
+        version: u32,
+        source: serde_json::Error,
+    },
+}
+
+/// A version of a stored type, which knows how to migrate data written with the version before it.
+///
+/// Each version names its predecessor, forming a chain that ends with [NoPrevious]:
+///
+/// ```ignore
+/// impl Upcast for ItemV0 {
+///     const VERSION: u32 = 0;
+///     type Previous = NoPrevious;
+///     fn upcast(previous: NoPrevious) -> Self { match previous {} }
+/// }
+///
+/// impl Upcast for Item {
+///     const VERSION: u32 = 1;
+///     type Previous = ItemV0;
+///     fn upcast(previous: ItemV0) -> Self { Item { name: previous.name, tags: vec![] } }
+/// }
+/// ```
+pub trait Upcast: DeserializeOwned {
+    const VERSION: u32;
+    type Previous: Upcast;
+
+    fn upcast(previous: Self::Previous) -> Self;
+
+    /// Deserialize `data` written with `version` of the type, upcasting it through the chain as needed.
+    fn from_version(version: u32, data: Value) -> Result<Self, VersionError> {
+        match version.cmp(&Self::VERSION) {
+            std::cmp::Ordering::Equal => serde_json::from_value(data)
+                .map_err(|source| VersionError::UnexpectedJson { version, source }),
+            std::cmp::Ordering::Less => {
+                Self::Previous::from_version(version, data).map(Self::upcast)
+            }
+            std::cmp::Ordering::Greater => Err(VersionError::Unsupported {
+                version,
+                current: Self::VERSION,
+            }),
+        }
+    }
+}
+
+/// The end of an [Upcast] chain.
+#[derive(Deserialize, Debug)]
+pub enum NoPrevious {}
+
+impl Upcast for NoPrevious {
+    const VERSION: u32 = 0;
+    type Previous = NoPrevious;
+
+    fn upcast(previous: NoPrevious) -> Self {
+        previous
+    }
+
+    fn from_version(version: u32, _data: Value) -> Result<Self, VersionError> {
+        Err(VersionError::NoUpcastPath { from: version })
+    }
+}
+
+/// Serialized as `{"schemaVersion": <T::VERSION>, "data": <data>}`, typically inside a [super::GzippedJSON].
+///
+/// Data written with an older version of `T` is upcast when it is read, and data that was stored before the
+/// envelope was introduced (i.e. anything but an object with exactly these two fields) is read as version 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Versioned<T> {
+    pub data: T,
+    stored_version: u32,
+}
+
+impl<T: Upcast> Versioned<T> {
+    pub fn new(data: T) -> Self {
+        Versioned {
+            data,
+            stored_version: T::VERSION,
+        }
+    }
+
+    /// The version the data was read with, or the current version for new data.
+    pub fn stored_version(&self) -> u32 {
+        self.stored_version
+    }
+
+    /// Whether the data was upcast when it was read, so it can be written back in the current version.
+    pub fn is_outdated(&self) -> bool {
+        self.stored_version < T::VERSION
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+
+    fn from_value(value: Value) -> Result<Self, VersionError> {
+        let (version, data) = match value {
+            Value::Object(mut envelope) if envelope.len() == 2 && envelope.contains_key("data") => {
+                match envelope.get("schemaVersion").and_then(Value::as_u64) {
+                    Some(version) => (
+                        u32::try_from(version).unwrap_or(u32::MAX),
+                        envelope.remove("data").unwrap_or_default(),
+                    ),
+                    None => (0, Value::Object(envelope)),
+                }
+            }
+            legacy => (0, legacy),
+        };
+        Ok(Versioned {
+            data: T::from_version(version, data)?,
+            stored_version: version,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    data: &'a T,
+}
+
+impl<T: Upcast + Serialize> Serialize for Versioned<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        Envelope {
+            schema_version: T::VERSION,
+            data: &self.data,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Upcast> Deserialize<'de> for Versioned<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Versioned::from_value(Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::model::AttributeValue;
+    use serde::{Deserialize, Serialize};
+    use serde_dynamo::{from_attribute_value, to_attribute_value};
+
+    use super::{NoPrevious, Upcast, VersionError, Versioned};
+    use crate::misc::GzippedJSON;
+
+    #[derive(Deserialize, Serialize)]
+    struct PeripheralV0 {
+        name: String,
+    }
+
+    impl Upcast for PeripheralV0 {
+        const VERSION: u32 = 0;
+        type Previous = NoPrevious;
+
+        fn upcast(previous: NoPrevious) -> Self {
+            match previous {}
+        }
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct PeripheralV1 {
+        #[serde(rename = "displayName")]
+        display_name: String,
+    }
+
+    impl Upcast for PeripheralV1 {
+        const VERSION: u32 = 1;
+        type Previous = PeripheralV0;
+
+        fn upcast(previous: PeripheralV0) -> Self {
+            PeripheralV1 {
+                display_name: previous.name,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    struct Peripheral {
+        #[serde(rename = "displayName")]
+        display_name: String,
+        tags: Vec<String>,
+    }
+
+    impl Upcast for Peripheral {
+        const VERSION: u32 = 2;
+        type Previous = PeripheralV1;
+
+        fn upcast(previous: PeripheralV1) -> Self {
+            Peripheral {
+                display_name: previous.display_name,
+                tags: Vec::new(),
+            }
+        }
+    }
+
+    fn read(attr_value: AttributeValue) -> Versioned<Peripheral> {
+        from_attribute_value::<_, GzippedJSON<Versioned<Peripheral>>>(attr_value)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn upcast_old_versions() {
+        let legacy = to_attribute_value(GzippedJSON(PeripheralV0 {
+            name: "sensor".to_string(),
+        }))
+        .unwrap();
+        let read_legacy = read(legacy);
+        assert_eq!(read_legacy.stored_version(), 0);
+        assert!(read_legacy.is_outdated());
+        assert_eq!(read_legacy.data.display_name, "sensor");
+
+        let v1 = to_attribute_value(GzippedJSON(Versioned::new(PeripheralV1 {
+            display_name: "sensor".to_string(),
+        })))
+        .unwrap();
+        let read_v1 = read(v1);
+        assert_eq!(read_v1.stored_version(), 1);
+        assert_eq!(read_v1.data, read_legacy.data);
+
+        // Written back in the current version
+        let current =
+            to_attribute_value(GzippedJSON(Versioned::new(read_v1.into_inner()))).unwrap();
+        let read_current = read(current);
+        assert!(!read_current.is_outdated());
+        assert_eq!(read_current.data.tags, Vec::<String>::new());
+    }
+
+    #[test]
+    fn envelope_format() {
+        let versioned = Versioned::new(PeripheralV1 {
+            display_name: "sensor".to_string(),
+        });
+        assert_eq!(
+            serde_json::to_value(&versioned).unwrap(),
+            serde_json::json!({"schemaVersion": 1, "data": {"displayName": "sensor"}})
+        );
+
+        let future = serde_json::json!({"schemaVersion": 3, "data": {}});
+        assert!(matches!(
+            Versioned::<Peripheral>::from_value(future),
+            Err(VersionError::Unsupported {
+                version: 3,
+                current: 2
+            })
+        ));
+        let broken = serde_json::json!({"schemaVersion": 1, "data": {"name": "sensor"}});
+        assert!(matches!(
+            Versioned::<Peripheral>::from_value(broken),
+            Err(VersionError::UnexpectedJson { version: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn no_upcast_path() {
+        // A chain that starts at version 1 cannot read data stored before the envelope was introduced
+        #[derive(Deserialize, Debug)]
+        struct Sensor {}
+
+        impl Upcast for Sensor {
+            const VERSION: u32 = 1;
+            type Previous = NoPrevious;
+
+            fn upcast(previous: NoPrevious) -> Self {
+                match previous {}
+            }
+        }
+
+        let error =
+            Versioned::<Sensor>::from_value(serde_json::json!({"name": "sensor"})).unwrap_err();
+        assert!(matches!(error, VersionError::NoUpcastPath { from: 0 }));
+        assert_eq!(
+            error.to_string(),
+            "no upcast path from schema version 0, it predates the oldest supported version"
+        );
+    }
+}
@@ -0,0 +1,11 @@
+use crate::services::in_region;
+use aws_sdk_kms::Client as KmsClient;
+use cached::proc_macro::cached;
+
+// Re-export
+pub use aws_sdk_kms;
+
+#[cached]
+pub async fn kms(region: Option<&'static str>) -> KmsClient {
+    KmsClient::new(&in_region(region).await)
+}
@@ -9,6 +9,8 @@ pub mod cognitoidentityprovider;
 pub mod dynamodb;
 #[cfg(feature = "services_iot")]
 pub mod iot;
+#[cfg(feature = "services_kms")]
+pub mod kms;
 #[cfg(feature = "services_lambda")]
 pub mod lambda;
 #[cfg(feature = "services_organizations")]
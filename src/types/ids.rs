@@ -0,0 +1,281 @@
+//! Newtypes for the ids passed around between services, so that e.g. a line id cannot be used as a group id.
+use std::{borrow::Borrow, fmt::Display, str::FromStr};
+
+use serde::de::Error as SerdeError;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Error {
+    #[error("{0} must not be empty")]
+    Empty(&'static str),
+    #[error("{0} must not contain whitespace or '#', but was `{1}`")]
+    InvalidCharacter(&'static str, String),
+    #[error("UserPoolId is expected to be of form `<region>_<id>`, but was `{0}`")]
+    MalformedUserPoolId(String),
+}
+
+/// Ids are used as (parts of) DynamoDB keys, where `#` separates the parts of composite keys.
+fn validate(kind: &'static str, id: &str) -> Result<(), Error> {
+    if id.is_empty() {
+        Err(Error::Empty(kind))
+    } else if id.contains(|c: char| c.is_whitespace() || c == '#') {
+        Err(Error::InvalidCharacter(kind, id.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+macro_rules! string_id {
+    ($(#[$meta:meta])* $name:ident, $graphql_name:literal, $graphql_description:literal) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(str: &str) -> Result<Self, Self::Err> {
+                validate(stringify!($name), str)?;
+                Ok($name(str.to_string()))
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = Error;
+
+            fn try_from(string: String) -> Result<Self, Self::Error> {
+                validate(stringify!($name), &string)?;
+                Ok($name(string))
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Borrow<str> for $name {
+            fn borrow(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_str(&self.0)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                String::deserialize(deserializer)?
+                    .try_into()
+                    .map_err(SerdeError::custom)
+            }
+        }
+
+        #[cfg(feature = "graphql")]
+        async_graphql::scalar!($name, $graphql_name, $graphql_description);
+    };
+}
+
+string_id!(
+    /// Id of a production line.
+    LineId,
+    "LineId",
+    "Id of a production line"
+);
+
+string_id!(
+    /// Id of a group of lines.
+    GroupId,
+    "GroupId",
+    "Id of a group of lines"
+);
+
+string_id!(
+    /// The `sub` claim of a Cognito user, which identifies the user within its user pool.
+    UserSub,
+    "UserSub",
+    "The sub of a user"
+);
+
+string_id!(
+    /// Id of a company, which has one or more user pools.
+    CompanyId,
+    "CompanyId",
+    "Id of a company"
+);
+
+/// Id of a Cognito user pool, e.g. `eu-west-1_lu59lbvt7`, which starts with the region of the pool.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct UserPoolId {
+    id: String,
+    /// Length of the region at the start of `id`
+    region_length: usize,
+}
+
+impl UserPoolId {
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+
+    /// The AWS region of the user pool, e.g. `eu-west-1`.
+    pub fn region(&self) -> &str {
+        &self.id[..self.region_length]
+    }
+}
+
+impl FromStr for UserPoolId {
+    type Err = Error;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        validate("UserPoolId", str)?;
+        match str.split_once('_') {
+            Some((region, id))
+                if !id.is_empty()
+                    && region.contains('-')
+                    && region
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                    && id.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                Ok(UserPoolId {
+                    id: str.to_string(),
+                    region_length: region.len(),
+                })
+            }
+            _ => Err(Error::MalformedUserPoolId(str.to_string())),
+        }
+    }
+}
+
+impl From<UserPoolId> for String {
+    fn from(id: UserPoolId) -> Self {
+        id.id
+    }
+}
+
+impl AsRef<str> for UserPoolId {
+    fn as_ref(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Borrow<str> for UserPoolId {
+    fn borrow(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Display for UserPoolId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+impl serde::Serialize for UserPoolId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.id)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for UserPoolId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(SerdeError::custom)
+    }
+}
+
+#[cfg(feature = "graphql")]
+async_graphql::scalar!(
+    UserPoolId,
+    "UserPoolId",
+    "Format: <region>_<id>, e.g. eu-west-1_lu59lbvt7"
+);
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, str::FromStr};
+
+    use super::{CompanyId, Error, LineId, UserPoolId};
+
+    #[test]
+    fn string_ids() {
+        let line_id = LineId::from_str("86d09530-0a54-11ec-b69f-cf2fbd32de70").unwrap();
+        assert_eq!(line_id.to_string(), "86d09530-0a54-11ec-b69f-cf2fbd32de70");
+        assert_eq!(LineId::from_str(""), Err(Error::Empty("LineId")));
+        assert!(LineId::from_str("line 1").is_err());
+        assert!(LineId::from_str("LINE#1").is_err());
+
+        // Sets of ids can be queried by &str
+        let line_ids = HashSet::from([line_id]);
+        assert!(line_ids.contains("86d09530-0a54-11ec-b69f-cf2fbd32de70"));
+
+        let json = serde_json::json!(["a", "b"]);
+        let back: Vec<LineId> = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(back).unwrap(), json);
+        assert!(serde_json::from_str::<LineId>(r#""""#).is_err());
+
+        let company_id = CompanyId::from_str("bb").unwrap();
+        assert_eq!(company_id.as_str(), "bb");
+        assert_eq!(CompanyId::from_str(""), Err(Error::Empty("CompanyId")));
+        assert!(CompanyId::from_str("COMPANY#bb").is_err());
+        assert_eq!(
+            serde_json::to_value(&company_id).unwrap(),
+            serde_json::json!("bb")
+        );
+        assert!(serde_json::from_str::<CompanyId>(r#""bb c""#).is_err());
+    }
+
+    #[test]
+    fn user_pool_id() {
+        let user_pool = UserPoolId::from_str("eu-west-1_lu59lbvt7").unwrap();
+        assert_eq!(user_pool.region(), "eu-west-1");
+        assert_eq!(user_pool.to_string(), "eu-west-1_lu59lbvt7");
+        for input in [
+            "pool",
+            "eu-west-1_",
+            "_lu59lbvt7",
+            "eu west_1",
+            "eu-west-1_a-b",
+        ] {
+            assert!(UserPoolId::from_str(input).is_err(), "{input}");
+        }
+
+        let back: UserPoolId = serde_json::from_value(serde_json::json!("us-east-2_abc")).unwrap();
+        assert_eq!(back.region(), "us-east-2");
+    }
+}
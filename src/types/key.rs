@@ -0,0 +1,383 @@
+//! Composite keys of our single table DynamoDB designs, e.g. `LINE#<line id>#PERIPHERAL#<peripheral id>`.
+//!
+//! ```ignore
+//! composite_key! {
+//!     /// Sort key of a peripheral on a line
+//!     pub struct LinePeripheralKey {
+//!         "LINE" => line_id: LineId,
+//!         "PERIPHERAL" => peripheral_id: PeripheralId,
+//!     }
+//! }
+//!
+//! let key = LinePeripheralKey { line_id, peripheral_id }.key()?;
+//! let back: LinePeripheralKey = key.parse()?;
+//! // All peripherals of the line, for a `begins_with` condition
+//! let prefix = LinePeripheralKey::prefix().line_id(&line_id)?.build();
+//! ```
+use std::{fmt::Display, marker::PhantomData, str::FromStr};
+
+use thiserror::Error;
+
+/// Separates the labels and components of a key.
+pub const SEPARATOR: char = '#';
+
+#[derive(Error, Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Error {
+    #[error("{0} component must not be empty")]
+    EmptyComponent(&'static str),
+    #[error("{label} component must not contain '#', but was `{value}`")]
+    InvalidComponent { label: &'static str, value: String },
+    #[error("key is expected to be of form `{expected}`, but was `{key}`")]
+    Malformed { expected: String, key: String },
+    #[error("invalid {label} component: {message}")]
+    Parse {
+        label: &'static str,
+        message: String,
+    },
+}
+
+/// A value that can be part of a [CompositeKey], implemented for everything that can be displayed and parsed.
+pub trait KeyComponent: Sized {
+    fn to_component(&self) -> String;
+    fn from_component(component: &str) -> Result<Self, String>;
+}
+
+impl<T: Display + FromStr> KeyComponent for T
+where
+    T::Err: Display,
+{
+    fn to_component(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_component(component: &str) -> Result<Self, String> {
+        component.parse().map_err(|e: T::Err| e.to_string())
+    }
+}
+
+/// A key of labelled components, usually declared with [crate::composite_key].
+pub trait CompositeKey: Sized {
+    /// The label of each component, in order.
+    const LABELS: &'static [&'static str];
+
+    fn components(&self) -> Vec<String>;
+    /// Build the key from as many components as there are labels, which have already been validated.
+    fn from_components(components: &[&str]) -> Result<Self, Error>;
+
+    /// Format the key, failing if a component is empty or contains the [SEPARATOR].
+    fn key(&self) -> Result<String, Error> {
+        let mut key = String::new();
+        for (label, component) in Self::LABELS.iter().zip(self.components()) {
+            push_component(&mut key, label, &component)?;
+        }
+        Ok(key)
+    }
+
+    fn parse_key(key: &str) -> Result<Self, Error> {
+        let malformed = || Error::Malformed {
+            expected: expected_form(Self::LABELS),
+            key: key.to_string(),
+        };
+        let parts: Vec<&str> = key.split(SEPARATOR).collect();
+        if parts.len() != Self::LABELS.len() * 2 {
+            return Err(malformed());
+        }
+        let mut components = Vec::with_capacity(Self::LABELS.len());
+        for (label, part) in Self::LABELS.iter().zip(parts.chunks(2)) {
+            if part[0] != *label {
+                return Err(malformed());
+            }
+            if part[1].is_empty() {
+                return Err(Error::EmptyComponent(label));
+            }
+            components.push(part[1]);
+        }
+        Self::from_components(&components)
+    }
+
+    /// Start a prefix of the key, for `begins_with` conditions.
+    fn prefix() -> KeyPrefix<Self> {
+        KeyPrefix {
+            prefix: String::new(),
+            key: PhantomData,
+        }
+    }
+}
+
+fn push_component(key: &mut String, label: &'static str, component: &str) -> Result<(), Error> {
+    if component.is_empty() {
+        return Err(Error::EmptyComponent(label));
+    }
+    if component.contains(SEPARATOR) {
+        return Err(Error::InvalidComponent {
+            label,
+            value: component.to_string(),
+        });
+    }
+    if !key.is_empty() {
+        key.push(SEPARATOR);
+    }
+    key.push_str(label);
+    key.push(SEPARATOR);
+    key.push_str(component);
+    Ok(())
+}
+
+fn expected_form(labels: &[&str]) -> String {
+    labels
+        .iter()
+        .map(|label| format!("{label}#<{}>", label.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join("#")
+}
+
+/// Used by [crate::composite_key] to parse a component.
+#[doc(hidden)]
+pub fn parse_component<T: KeyComponent>(label: &'static str, component: &str) -> Result<T, Error> {
+    T::from_component(component).map_err(|message| Error::Parse { label, message })
+}
+
+/// Used by [crate::composite_key] to reject labels containing the [SEPARATOR] at compile time.
+#[doc(hidden)]
+pub const fn is_valid_label(label: &str) -> bool {
+    let bytes = label.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == SEPARATOR as u8 {
+            return false;
+        }
+        i += 1;
+    }
+    !bytes.is_empty()
+}
+
+/// The first `N` components of a [CompositeKey], followed by the label of the next component.
+///
+/// E.g. `LINE#<line id>#PERIPHERAL#` for the first component of a `LINE#..#PERIPHERAL#..` key, so that a
+/// `begins_with` condition does not also match line ids that merely start with the same characters.
+///
+/// [crate::composite_key] generates a method per component, named after its field, which is only
+/// available at that component's position, e.g. `LinePeripheralKey::prefix().line_id(&line_id)?`.
+pub struct KeyPrefix<K, const N: usize = 0> {
+    prefix: String,
+    key: PhantomData<fn() -> K>,
+}
+
+impl<K: CompositeKey, const N: usize> KeyPrefix<K, N> {
+    /// Used by [crate::composite_key] to add the component at position `N`.
+    #[doc(hidden)]
+    pub fn push<const NEXT: usize>(
+        mut self,
+        component: &impl KeyComponent,
+    ) -> Result<KeyPrefix<K, NEXT>, Error> {
+        push_component(&mut self.prefix, K::LABELS[N], &component.to_component())?;
+        Ok(KeyPrefix {
+            prefix: self.prefix,
+            key: PhantomData,
+        })
+    }
+
+    pub fn build(mut self) -> String {
+        if let Some(label) = K::LABELS.get(N) {
+            if !self.prefix.is_empty() {
+                self.prefix.push(SEPARATOR);
+            }
+            self.prefix.push_str(label);
+            self.prefix.push(SEPARATOR);
+        }
+        self.prefix
+    }
+}
+
+/// Declare a struct that is formatted and parsed as a [CompositeKey](crate::types::key::CompositeKey) of
+/// `LABEL#<component>` pairs joined by `#`, see [crate::types::key].
+#[macro_export]
+macro_rules! composite_key {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($label:literal => $field:ident: $ty:ty),+ $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        $vis struct $name {
+            $(pub $field: $ty),+
+        }
+
+        $(const _: () = assert!(
+            $crate::types::key::is_valid_label($label),
+            concat!("composite key label `", $label, "` must not be empty or contain '#'"),
+        );)+
+
+        $crate::__composite_key_prefix!($vis $name; []; $($label => $field: $ty),+);
+
+        impl $crate::types::key::CompositeKey for $name {
+            const LABELS: &'static [&'static str] = &[$($label),+];
+
+            fn components(&self) -> Vec<String> {
+                vec![$($crate::types::key::KeyComponent::to_component(&self.$field)),+]
+            }
+
+            fn from_components(components: &[&str]) -> Result<Self, $crate::types::key::Error> {
+                let mut components = components.iter();
+                Ok($name {
+                    $($field: $crate::types::key::parse_component(
+                        $label,
+                        components.next().copied().unwrap_or_default(),
+                    )?),+
+                })
+            }
+        }
+
+        impl ::std::str::FromStr for $name {
+            type Err = $crate::types::key::Error;
+
+            fn from_str(key: &str) -> Result<Self, Self::Err> {
+                <Self as $crate::types::key::CompositeKey>::parse_key(key)
+            }
+        }
+    };
+}
+
+/// Generates the [KeyPrefix](crate::types::key::KeyPrefix) method of each component of a
+/// [crate::composite_key], counting the preceding components as `1`s to get its position.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __composite_key_prefix {
+    ($vis:vis $name:ident; [$($before:tt)*];) => {};
+    (
+        $vis:vis $name:ident; [$($before:tt)*];
+        $label:literal => $field:ident: $ty:ty $(, $rest_label:literal => $rest_field:ident: $rest_ty:ty)*
+    ) => {
+        impl $crate::types::key::KeyPrefix<$name, { 0 $(+ $before)* }> {
+            #[doc = concat!("Add the `", $label, "` component.")]
+            #[allow(dead_code)]
+            $vis fn $field(
+                self,
+                $field: &$ty,
+            ) -> Result<$crate::types::key::KeyPrefix<$name, { 1 $(+ $before)* }>, $crate::types::key::Error> {
+                self.push($field)
+            }
+        }
+
+        $crate::__composite_key_prefix!($vis $name; [$($before)* 1]; $($rest_label => $rest_field: $rest_ty),*);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompositeKey, Error};
+    use crate::types::{ids::LineId, peripheral_id::PeripheralId};
+
+    crate::composite_key! {
+        struct LinePeripheralKey {
+            "LINE" => line_id: LineId,
+            "PERIPHERAL" => peripheral_id: PeripheralId,
+        }
+    }
+
+    crate::composite_key! {
+        struct VersionKey {
+            "NAME" => name: String,
+            "VERSION" => version: u32,
+        }
+    }
+
+    fn key() -> LinePeripheralKey {
+        LinePeripheralKey {
+            line_id: "86d09530".parse().unwrap(),
+            peripheral_id: "abc-1".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn format_and_parse() {
+        let formatted = key().key().unwrap();
+        assert_eq!(formatted, "LINE#86d09530#PERIPHERAL#abc-1");
+        assert_eq!(formatted.parse::<LinePeripheralKey>().unwrap(), key());
+
+        let version = VersionKey {
+            name: "config".to_string(),
+            version: 3,
+        };
+        assert_eq!(version.key().unwrap(), "NAME#config#VERSION#3");
+        assert_eq!(
+            "NAME#config#VERSION#3".parse::<VersionKey>().unwrap(),
+            version
+        );
+    }
+
+    #[test]
+    fn invalid_keys() {
+        let with_separator = VersionKey {
+            name: "a#b".to_string(),
+            version: 1,
+        };
+        assert_eq!(
+            with_separator.key(),
+            Err(Error::InvalidComponent {
+                label: "NAME",
+                value: "a#b".to_string()
+            })
+        );
+        for key in [
+            "LINE#86d09530",
+            "LINE#86d09530#PERIPHERAL#abc-1#EXTRA#1",
+            "GROUP#86d09530#PERIPHERAL#abc-1",
+        ] {
+            assert!(
+                matches!(
+                    key.parse::<LinePeripheralKey>(),
+                    Err(Error::Malformed { .. })
+                ),
+                "{key}"
+            );
+        }
+        assert_eq!(
+            "LINE##PERIPHERAL#abc-1".parse::<LinePeripheralKey>(),
+            Err(Error::EmptyComponent("LINE"))
+        );
+        assert!(matches!(
+            "NAME#config#VERSION#latest".parse::<VersionKey>(),
+            Err(Error::Parse {
+                label: "VERSION",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(LinePeripheralKey::prefix().build(), "LINE#");
+        let line_id = key().line_id;
+        assert_eq!(
+            LinePeripheralKey::prefix()
+                .line_id(&line_id)
+                .unwrap()
+                .build(),
+            "LINE#86d09530#PERIPHERAL#"
+        );
+        let full = LinePeripheralKey::prefix()
+            .line_id(&line_id)
+            .and_then(|prefix| prefix.peripheral_id(&key().peripheral_id))
+            .unwrap();
+        assert_eq!(full.build(), key().key().unwrap());
+        assert_eq!(
+            VersionKey::prefix()
+                .name(&"a#b".to_string())
+                .map(|prefix| prefix.build()),
+            Err(Error::InvalidComponent {
+                label: "NAME",
+                value: "a#b".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn labels() {
+        assert!(super::is_valid_label("LINE"));
+        assert!(!super::is_valid_label("LINE#"));
+        assert!(!super::is_valid_label(""));
+    }
+}
@@ -0,0 +1,350 @@
+use std::{borrow::Borrow, collections::HashMap, fmt::Display, str::FromStr};
+
+use serde::de::Error as SerdeError;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Error {
+    #[error("Language must not be empty")]
+    Empty,
+    #[error("Language is expected to be a BCP 47 tag like `en` or `de-CH`, but was `{0}`")]
+    Invalid(String),
+}
+
+/// A BCP 47 language tag, e.g. `en`, `de-CH` or `zh-Hant-TW`, in canonical case.
+///
+/// Tags are validated and normalized on parse, `_` is accepted as separator as some clients send `en_US`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Language(String);
+
+impl Language {
+    /// The language used when nothing else is known.
+    pub fn english() -> Self {
+        Language("en".to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The primary language subtag, e.g. `de` for `de-CH`.
+    pub fn primary(&self) -> &str {
+        self.0.split('-').next().unwrap_or_default()
+    }
+
+    /// The tag and all its less specific prefixes, most specific first, e.g. `zh-Hant-TW`, `zh-Hant`, `zh`.
+    ///
+    /// Extensions and private use are dropped as a whole, e.g. `de-DE-u-co-phonebk` falls back to `de-DE`.
+    pub fn fallbacks(&self) -> impl Iterator<Item = &str> {
+        let tag = self.0.as_str();
+        // Everything from the first singleton (e.g. `u` or `x`) on is an extension or private use
+        let base = tag
+            .match_indices('-')
+            .map(|(start, _)| start)
+            .find(|&start| matches!(tag[start + 1..].split('-').next(), Some(s) if s.len() == 1))
+            .map_or(tag, |end| &tag[..end]);
+        (base.len() < tag.len())
+            .then_some(tag)
+            .into_iter()
+            .chain(std::iter::successors(Some(base), |tag| {
+                tag.rfind('-').map(|end| &tag[..end])
+            }))
+    }
+
+    /// Resolve the language to use: the requested language, else the language of the user, else the default
+    /// language of the company, else [Language::english].
+    pub fn negotiate(
+        requested: Option<&Language>,
+        user: Option<&Language>,
+        company_default: Option<&Language>,
+    ) -> Language {
+        requested
+            .or(user)
+            .or(company_default)
+            .cloned()
+            .unwrap_or_else(Language::english)
+    }
+}
+
+impl Default for Language {
+    fn default() -> Self {
+        Language::english()
+    }
+}
+
+fn is_alpha(subtag: &str, lengths: std::ops::RangeInclusive<usize>) -> bool {
+    lengths.contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+fn is_alphanumeric(subtag: &str, lengths: std::ops::RangeInclusive<usize>) -> bool {
+    lengths.contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Validate `language[-script][-region][-variant]*[-singleton-extension]*` and bring the subtags to canonical case.
+fn canonicalize(tag: &str) -> Option<String> {
+    let mut subtags = tag.split(['-', '_']).peekable();
+    let language = subtags.next().filter(|s| is_alpha(s, 2..=3))?;
+    let mut canonical = vec![language.to_ascii_lowercase()];
+
+    if let Some(script) = subtags.next_if(|s| is_alpha(s, 4..=4)) {
+        let mut script = script.to_ascii_lowercase();
+        script[..1].make_ascii_uppercase();
+        canonical.push(script);
+    }
+    if let Some(region) = subtags
+        .next_if(|s| is_alpha(s, 2..=2) || (s.len() == 3 && s.chars().all(|c| c.is_ascii_digit())))
+    {
+        canonical.push(region.to_ascii_uppercase());
+    }
+    while let Some(variant) = subtags.next_if(|s| {
+        is_alphanumeric(s, 5..=8) || (s.len() == 4 && s.starts_with(|c: char| c.is_ascii_digit()))
+    }) {
+        canonical.push(variant.to_ascii_lowercase());
+    }
+    // Extensions and private use, e.g. `u-co-phonebk` or `x-custom`
+    while let Some(singleton) = subtags.next() {
+        if !is_alphanumeric(singleton, 1..=1) {
+            return None;
+        }
+        canonical.push(singleton.to_ascii_lowercase());
+        let private_use = singleton.eq_ignore_ascii_case("x");
+        let mut has_subtag = false;
+        // Everything after `x` is private use, which may also consist of single characters
+        while let Some(subtag) = subtags.next_if(|s| private_use || s.len() > 1) {
+            if !is_alphanumeric(subtag, if private_use { 1..=8 } else { 2..=8 }) {
+                return None;
+            }
+            canonical.push(subtag.to_ascii_lowercase());
+            has_subtag = true;
+        }
+        if !has_subtag {
+            return None;
+        }
+    }
+    Some(canonical.join("-"))
+}
+
+impl FromStr for Language {
+    type Err = Error;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        if str.is_empty() {
+            return Err(Error::Empty);
+        }
+        canonicalize(str)
+            .map(Language)
+            .ok_or_else(|| Error::Invalid(str.to_string()))
+    }
+}
+
+impl AsRef<str> for Language {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Language {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Language {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl serde::Serialize for Language {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Language {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(SerdeError::custom)
+    }
+}
+
+#[cfg(feature = "graphql")]
+async_graphql::scalar!(
+    Language,
+    "Language",
+    "A BCP 47 language tag, e.g. en or de-CH"
+);
+
+/// Translated messages by language and key, in the same shape as the resources of our TS services:
+///
+/// ```json
+/// {"en": {"greeting": "Hello {{name}}"}, "de": {"greeting": "Hallo {{name}}"}}
+/// ```
+///
+/// Lookups fall back to less specific tags of the language and then to [Language::english].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct Catalog(HashMap<Language, HashMap<String, String>>);
+
+impl Catalog {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add or replace a message.
+    pub fn message(mut self, language: Language, key: &str, message: &str) -> Self {
+        self.0
+            .entry(language)
+            .or_default()
+            .insert(key.to_string(), message.to_string());
+        self
+    }
+
+    /// The message for the key in the language, or in the closest language it is translated to.
+    pub fn translate(&self, language: &Language, key: &str) -> Option<&str> {
+        language
+            .fallbacks()
+            .chain([Language::english().as_str()])
+            .find_map(|tag| self.0.get(tag)?.get(key))
+            .map(String::as_str)
+    }
+
+    /// [Catalog::translate] with `{{name}}` placeholders replaced by the value of `name` in `args`.
+    pub fn translate_with(
+        &self,
+        language: &Language,
+        key: &str,
+        args: &[(&str, &dyn Display)],
+    ) -> Option<String> {
+        let mut rest = self.translate(language, key)?;
+        // A single pass over the message, so placeholders in the values of `args` are left alone
+        let mut translated = String::with_capacity(rest.len());
+        while let Some(start) = rest.find("{{") {
+            translated.push_str(&rest[..start]);
+            let placeholder = &rest[start + 2..];
+            let value = placeholder.find("}}").and_then(|end| {
+                let name = &placeholder[..end];
+                let (_, value) = args.iter().find(|(arg, _)| *arg == name)?;
+                Some((end, value))
+            });
+            match value {
+                Some((end, value)) => {
+                    translated.push_str(&value.to_string());
+                    rest = &placeholder[end + 2..];
+                }
+                None => {
+                    translated.push('{');
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+        translated.push_str(rest);
+        Some(translated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{Catalog, Error, Language};
+
+    fn language(tag: &str) -> Language {
+        tag.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_languages() {
+        for (input, canonical) in [
+            ("en", "en"),
+            ("DE-ch", "de-CH"),
+            ("en_US", "en-US"),
+            ("zh-hant-tw", "zh-Hant-TW"),
+            ("es-419", "es-419"),
+            ("sl-rozaj-biske", "sl-rozaj-biske"),
+            ("de-DE-u-co-phonebk", "de-DE-u-co-phonebk"),
+            ("en-x-custom", "en-x-custom"),
+        ] {
+            assert_eq!(language(input).as_str(), canonical, "{input}");
+        }
+        assert_eq!(Language::from_str(""), Err(Error::Empty));
+        for input in ["e", "english", "en-", "en--US", "en-US-x", "en US", "12"] {
+            assert!(Language::from_str(input).is_err(), "{input}");
+        }
+        let back: Language = serde_json::from_value(serde_json::json!("sv_se")).unwrap();
+        assert_eq!(serde_json::to_value(back).unwrap(), "sv-SE");
+    }
+
+    #[test]
+    fn negotiate() {
+        let (requested, user, company) = (language("de-CH"), language("sv"), language("fi"));
+        assert_eq!(
+            Language::negotiate(Some(&requested), Some(&user), Some(&company)),
+            requested
+        );
+        assert_eq!(Language::negotiate(None, Some(&user), Some(&company)), user);
+        assert_eq!(Language::negotiate(None, None, Some(&company)), company);
+        assert_eq!(Language::negotiate(None, None, None), Language::english());
+
+        let private = language("zh-Hant-TW-x-custom");
+        let fallbacks: Vec<&str> = private.fallbacks().collect();
+        assert_eq!(
+            fallbacks,
+            ["zh-Hant-TW-x-custom", "zh-Hant-TW", "zh-Hant", "zh"]
+        );
+        let extension = language("de-DE-u-co-phonebk-x-a-b");
+        let fallbacks: Vec<&str> = extension.fallbacks().collect();
+        assert_eq!(fallbacks, ["de-DE-u-co-phonebk-x-a-b", "de-DE", "de"]);
+        let plain = language("sl-rozaj-biske");
+        let fallbacks: Vec<&str> = plain.fallbacks().collect();
+        assert_eq!(fallbacks, ["sl-rozaj-biske", "sl-rozaj", "sl"]);
+    }
+
+    #[test]
+    fn catalog() {
+        let catalog: Catalog = serde_json::from_value(serde_json::json!({
+            "en": {"greeting": "Hello {{name}}", "bye": "Bye"},
+            "de": {"greeting": "Hallo {{name}}"},
+            "de-CH": {"greeting": "Grüezi {{name}}"},
+        }))
+        .unwrap();
+        assert_eq!(
+            catalog.translate(&language("de-CH"), "greeting"),
+            Some("Grüezi {{name}}")
+        );
+        assert_eq!(
+            catalog.translate(&language("de-AT"), "greeting"),
+            Some("Hallo {{name}}")
+        );
+        assert_eq!(catalog.translate(&language("de-AT"), "bye"), Some("Bye"));
+        assert_eq!(catalog.translate(&language("sv"), "missing"), None);
+        assert_eq!(
+            catalog
+                .clone()
+                .message(language("sv"), "greeting", "Hej {{name}}")
+                .translate_with(&language("sv-FI"), "greeting", &[("name", &"Ada")]),
+            Some("Hej Ada".to_string())
+        );
+
+        let catalog = Catalog::new().message(
+            language("en"),
+            "moved",
+            "{{{from}}} moved {{what}} to {{to}} {{missing}}",
+        );
+        assert_eq!(
+            catalog.translate_with(
+                &language("en"),
+                "moved",
+                &[("from", &"{{to}}"), ("what", &"{{from}}"), ("to", &"B")]
+            ),
+            Some("{{{to}}} moved {{from}} to B {{missing}}".to_string())
+        );
+    }
+}
@@ -1 +1,6 @@
+pub mod ids;
+pub mod key;
+pub mod language;
 pub mod peripheral_id;
+
+pub use language::Language;
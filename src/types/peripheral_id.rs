@@ -1,9 +1,17 @@
-use std::{fmt::Display, str::FromStr};
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::{btree_set, BTreeSet},
+    fmt::Display,
+    hash::{Hash, Hasher},
+    ops::Bound,
+    str::FromStr,
+};
 
 use serde::de::Error as SerdeError;
 use thiserror::Error;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PeripheralId {
     uuid: String,
     index: String,
@@ -19,23 +27,30 @@ pub enum Error {
     EmptyIndex,
     #[error("Index must not contain '#'")]
     IndexContainsPound,
+    #[error("PeripheralId attribute must be a string")]
+    NotAString,
     #[error("{0}")]
     Generic(String),
 }
 
+fn validate(uuid: &str, index: &str) -> Result<(), Error> {
+    if uuid.is_empty() {
+        Err(Error::EmptyUUID)
+    } else if uuid.contains('-') {
+        Err(Error::MalformedUUID)
+    } else if index.contains('#') {
+        Err(Error::IndexContainsPound)
+    } else if index.is_empty() {
+        Err(Error::EmptyIndex)
+    } else {
+        Ok(())
+    }
+}
+
 impl PeripheralId {
     pub fn new(uuid: String, index: String) -> Result<PeripheralId, Error> {
-        if uuid.is_empty() {
-            Err(Error::EmptyUUID)
-        } else if uuid.contains('-') {
-            Err(Error::MalformedUUID)
-        } else if index.contains('#') {
-            Err(Error::IndexContainsPound)
-        } else if index.is_empty() {
-            Err(Error::EmptyIndex)
-        } else {
-            Ok(PeripheralId { uuid, index })
-        }
+        validate(&uuid, &index)?;
+        Ok(PeripheralId { uuid, index })
     }
     pub fn uuid(&self) -> &str {
         &self.uuid
@@ -43,6 +58,300 @@ impl PeripheralId {
     pub fn index(&self) -> &str {
         &self.index
     }
+    pub fn as_id_ref(&self) -> PeripheralIdRef<'_> {
+        PeripheralIdRef {
+            uuid: &self.uuid,
+            index: &self.index,
+        }
+    }
+}
+
+/// A [PeripheralId] that borrows its uuid and index, e.g. from the string it was parsed from.
+///
+/// Sets and maps of [PeripheralId] can be queried with it without allocating, as both are [PeripheralIdLike]:
+///
+/// ```ignore
+/// let peripheral_id = PeripheralIdRef::parse("abc-1")?;
+/// peripheral_ids.contains(&peripheral_id as &dyn PeripheralIdLike)
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeripheralIdRef<'a> {
+    uuid: &'a str,
+    index: &'a str,
+}
+
+impl<'a> PeripheralIdRef<'a> {
+    pub fn new(uuid: &'a str, index: &'a str) -> Result<Self, Error> {
+        validate(uuid, index)?;
+        Ok(PeripheralIdRef { uuid, index })
+    }
+
+    /// Like [PeripheralId::from_str], but borrowing from `str`.
+    pub fn parse(str: &'a str) -> Result<Self, Error> {
+        match str.split_once('-') {
+            Some((uuid, index)) => PeripheralIdRef::new(uuid, index),
+            _ => Err(Error::Generic(format!(
+                "PeripheralId is expected to be of form `<uuid>-<index>`, but was `{}`",
+                str
+            ))),
+        }
+    }
+
+    pub fn uuid(&self) -> &'a str {
+        self.uuid
+    }
+
+    pub fn index(&self) -> &'a str {
+        self.index
+    }
+
+    pub fn to_peripheral_id(&self) -> PeripheralId {
+        PeripheralId {
+            uuid: self.uuid.to_string(),
+            index: self.index.to_string(),
+        }
+    }
+}
+
+impl From<PeripheralIdRef<'_>> for PeripheralId {
+    fn from(peripheral_id: PeripheralIdRef<'_>) -> Self {
+        peripheral_id.to_peripheral_id()
+    }
+}
+
+impl Display for PeripheralIdRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}-{}", self.uuid, self.index))
+    }
+}
+
+/// Either a [PeripheralId] or a [PeripheralIdRef], which hash and compare the same so that one can be looked up
+/// by the other through `dyn PeripheralIdLike`.
+pub trait PeripheralIdLike {
+    fn uuid(&self) -> &str;
+    fn index(&self) -> &str;
+}
+
+impl PeripheralIdLike for PeripheralId {
+    fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn index(&self) -> &str {
+        &self.index
+    }
+}
+
+impl PeripheralIdLike for PeripheralIdRef<'_> {
+    fn uuid(&self) -> &str {
+        self.uuid
+    }
+
+    fn index(&self) -> &str {
+        self.index
+    }
+}
+
+impl PartialEq for dyn PeripheralIdLike + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.uuid() == other.uuid() && self.index() == other.index()
+    }
+}
+
+impl Eq for dyn PeripheralIdLike + '_ {}
+
+impl Hash for dyn PeripheralIdLike + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.uuid().hash(state);
+        self.index().hash(state);
+    }
+}
+
+/// Must hash like `dyn PeripheralIdLike`, for the [Borrow] impl.
+impl Hash for PeripheralId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self as &dyn PeripheralIdLike).hash(state)
+    }
+}
+
+impl<'a> Borrow<dyn PeripheralIdLike + 'a> for PeripheralId {
+    fn borrow(&self) -> &(dyn PeripheralIdLike + 'a) {
+        self
+    }
+}
+
+/// Ordered by uuid, then by index in natural order, i.e. with numbers compared by value so `2` sorts before `10`.
+impl Ord for dyn PeripheralIdLike + '_ {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.uuid()
+            .cmp(other.uuid())
+            .then_with(|| natural_cmp(self.index(), other.index()))
+    }
+}
+
+impl PartialOrd for dyn PeripheralIdLike + '_ {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PeripheralId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self as &dyn PeripheralIdLike).cmp(other)
+    }
+}
+
+impl PartialOrd for PeripheralId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PeripheralIdRef<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self as &dyn PeripheralIdLike).cmp(other)
+    }
+}
+
+impl PartialOrd for PeripheralIdRef<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Compare runs of digits by their value and everything else as text. Strings that only differ in leading zeros
+/// are ordered as text, so that only equal strings compare equal.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_rest, mut b_rest) = (a, b);
+    while !a_rest.is_empty() && !b_rest.is_empty() {
+        let (a_chunk, a_next) = split_chunk(a_rest);
+        let (b_chunk, b_next) = split_chunk(b_rest);
+        let is_number = |chunk: &str| chunk.starts_with(|c: char| c.is_ascii_digit());
+        let ordering = if is_number(a_chunk) && is_number(b_chunk) {
+            let a_number = a_chunk.trim_start_matches('0');
+            let b_number = b_chunk.trim_start_matches('0');
+            a_number
+                .len()
+                .cmp(&b_number.len())
+                .then_with(|| a_number.cmp(b_number))
+        } else {
+            a_chunk.cmp(b_chunk)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        (a_rest, b_rest) = (a_next, b_next);
+    }
+    a_rest
+        .is_empty()
+        .cmp(&b_rest.is_empty())
+        .reverse()
+        .then_with(|| a.cmp(b))
+}
+
+/// Split off the leading run of digits or non-digits.
+fn split_chunk(s: &str) -> (&str, &str) {
+    let is_digit = s.starts_with(|c: char| c.is_ascii_digit());
+    let end = s
+        .find(|c: char| c.is_ascii_digit() != is_digit)
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// An ordered set of [PeripheralId]s, where all indexes of a uuid can be found without scanning the whole set.
+///
+/// Lookups take a `&dyn PeripheralIdLike`, i.e. a [PeripheralId] or a [PeripheralIdRef].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct PeripheralSet(BTreeSet<PeripheralId>);
+
+impl PeripheralSet {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns whether the peripheral id was not already in the set.
+    pub fn insert(&mut self, peripheral_id: PeripheralId) -> bool {
+        self.0.insert(peripheral_id)
+    }
+
+    /// Returns whether the peripheral id was in the set.
+    pub fn remove(&mut self, peripheral_id: &dyn PeripheralIdLike) -> bool {
+        self.0.remove(peripheral_id)
+    }
+
+    pub fn contains(&self, peripheral_id: &dyn PeripheralIdLike) -> bool {
+        self.0.contains(peripheral_id)
+    }
+
+    /// The peripheral ids with the uuid, ordered by index.
+    pub fn by_uuid<'a>(&'a self, uuid: &'a str) -> impl Iterator<Item = &'a PeripheralId> + 'a {
+        // An empty index sorts before all valid ones
+        let start = PeripheralIdRef { uuid, index: "" };
+        self.0
+            .range::<dyn PeripheralIdLike, _>((
+                Bound::Included(&start as &dyn PeripheralIdLike),
+                Bound::Unbounded,
+            ))
+            .take_while(move |peripheral_id| peripheral_id.uuid() == uuid)
+    }
+
+    pub fn iter(&self) -> btree_set::Iter<'_, PeripheralId> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn retain<F: FnMut(&PeripheralId) -> bool>(&mut self, f: F) {
+        self.0.retain(f)
+    }
+
+    pub fn is_subset(&self, other: &PeripheralSet) -> bool {
+        self.0.is_subset(&other.0)
+    }
+
+    pub fn intersection<'a>(
+        &'a self,
+        other: &'a PeripheralSet,
+    ) -> impl Iterator<Item = &'a PeripheralId> + 'a {
+        self.0.intersection(&other.0)
+    }
+}
+
+impl FromIterator<PeripheralId> for PeripheralSet {
+    fn from_iter<I: IntoIterator<Item = PeripheralId>>(iter: I) -> Self {
+        PeripheralSet(iter.into_iter().collect())
+    }
+}
+
+impl Extend<PeripheralId> for PeripheralSet {
+    fn extend<I: IntoIterator<Item = PeripheralId>>(&mut self, iter: I) {
+        self.0.extend(iter)
+    }
+}
+
+impl IntoIterator for PeripheralSet {
+    type Item = PeripheralId;
+    type IntoIter = btree_set::IntoIter<PeripheralId>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a PeripheralSet {
+    type Item = &'a PeripheralId;
+    type IntoIter = btree_set::Iter<'a, PeripheralId>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
 }
 
 impl<'de> serde::Deserialize<'de> for PeripheralId {
@@ -50,8 +359,22 @@ impl<'de> serde::Deserialize<'de> for PeripheralId {
     where
         D: serde::Deserializer<'de>,
     {
-        let str = <&str>::deserialize(deserializer)?;
-        Self::from_str(str).map_err(SerdeError::custom)
+        // Visit rather than deserializing a `&str`, which only borrowing deserializers can provide
+        deserializer.deserialize_str(PeripheralIdVisitor)
+    }
+}
+
+struct PeripheralIdVisitor;
+
+impl serde::de::Visitor<'_> for PeripheralIdVisitor {
+    type Value = PeripheralId;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a string of form `<uuid>-<index>`")
+    }
+
+    fn visit_str<E: SerdeError>(self, v: &str) -> Result<Self::Value, E> {
+        PeripheralId::from_str(v).map_err(E::custom)
     }
 }
 
@@ -59,14 +382,7 @@ impl FromStr for PeripheralId {
     type Err = Error;
 
     fn from_str(str: &str) -> Result<Self, Self::Err> {
-        let split = str.split_once('-');
-        match split {
-            Some((uuid, index)) => PeripheralId::new(uuid.to_string(), index.to_string()),
-            _ => Err(Error::Generic(format!(
-                "PeripheralId is expected to be of form `<uuid>-<index>`, but was `{}`",
-                str
-            ))),
-        }
+        PeripheralIdRef::parse(str).map(PeripheralId::from)
     }
 }
 
@@ -88,11 +404,39 @@ impl Display for PeripheralId {
 #[cfg(feature = "graphql")]
 async_graphql::scalar!(PeripheralId, "PeripheralId", "Format: <uuid>-<index>");
 
+#[cfg(feature = "services_dynamodb")]
+impl From<PeripheralId> for aws_sdk_dynamodb::model::AttributeValue {
+    fn from(peripheral_id: PeripheralId) -> Self {
+        aws_sdk_dynamodb::model::AttributeValue::S(peripheral_id.to_string())
+    }
+}
+
+#[cfg(feature = "services_dynamodb")]
+impl TryFrom<&aws_sdk_dynamodb::model::AttributeValue> for PeripheralId {
+    type Error = Error;
+
+    fn try_from(value: &aws_sdk_dynamodb::model::AttributeValue) -> Result<Self, Self::Error> {
+        value
+            .as_s()
+            .map_err(|_| Error::NotAString)
+            .and_then(|s| s.parse())
+    }
+}
+
+#[cfg(feature = "services_dynamodb")]
+impl TryFrom<aws_sdk_dynamodb::model::AttributeValue> for PeripheralId {
+    type Error = Error;
+
+    fn try_from(value: aws_sdk_dynamodb::model::AttributeValue) -> Result<Self, Self::Error> {
+        PeripheralId::try_from(&value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{collections::HashSet, str::FromStr};
 
-    use super::PeripheralId;
+    use super::{PeripheralId, PeripheralIdLike, PeripheralIdRef, PeripheralSet};
 
     #[test]
     fn is_not_peripheral_id() {
@@ -102,6 +446,102 @@ mod tests {
         }
     }
 
+    #[test]
+    fn borrowed_peripheral_id() {
+        for input in ["asd", "1_23", "-hej", "-", "asd-", "a-b#c"] {
+            assert_eq!(
+                PeripheralIdRef::parse(input).unwrap_err(),
+                PeripheralId::from_str(input).unwrap_err()
+            );
+        }
+        let borrowed = PeripheralIdRef::parse("1 2-3-4").unwrap();
+        assert_eq!((borrowed.uuid(), borrowed.index()), ("1 2", "3-4"));
+        let owned = PeripheralId::from(borrowed);
+        assert_eq!(owned.as_id_ref(), borrowed);
+        assert_eq!(owned.to_string(), borrowed.to_string());
+
+        let peripheral_ids = HashSet::from([owned]);
+        assert!(peripheral_ids.contains(&borrowed as &dyn PeripheralIdLike));
+        let other = PeripheralIdRef::parse("1 2-3").unwrap();
+        assert!(!peripheral_ids.contains(&other as &dyn PeripheralIdLike));
+    }
+
+    #[test]
+    fn natural_order() {
+        let mut peripheral_ids: Vec<PeripheralId> =
+            ["b-1", "a-10", "a-2", "a-02", "a-x1", "a-1a", "a-1"]
+                .iter()
+                .map(|id| id.parse().unwrap())
+                .collect();
+        peripheral_ids.sort();
+        let sorted: Vec<String> = peripheral_ids.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            sorted,
+            ["a-1", "a-1a", "a-02", "a-2", "a-10", "a-x1", "b-1"]
+        );
+    }
+
+    #[test]
+    fn peripheral_set() {
+        let mut set: PeripheralSet = ["ab-2", "a-10", "a-2", "b-1"]
+            .iter()
+            .map(|id| id.parse().unwrap())
+            .collect();
+        let by_uuid: Vec<String> = set.by_uuid("a").map(ToString::to_string).collect();
+        assert_eq!(by_uuid, ["a-2", "a-10"]);
+        assert_eq!(set.by_uuid("c").count(), 0);
+
+        let lookup = PeripheralIdRef::parse("a-10").unwrap();
+        assert!(set.contains(&lookup));
+        assert!(set.remove(&lookup));
+        assert!(!set.contains(&lookup));
+        assert_eq!(set.len(), 3);
+
+        let json = serde_json::to_value(&set).unwrap();
+        assert_eq!(json, serde_json::json!(["a-2", "ab-2", "b-1"]));
+        assert_eq!(serde_json::from_value::<PeripheralSet>(json).unwrap(), set);
+    }
+
+    #[test]
+    fn deserialize_owned_and_borrowed() {
+        let expected = PeripheralId::from_str("abc-1").unwrap();
+        let borrowed: PeripheralId = serde_json::from_str(r#""abc-1""#).unwrap();
+        assert_eq!(borrowed, expected);
+        // Escapes can only be deserialized into an owned string
+        let escaped: PeripheralId = serde_json::from_str(r#""ab\u0063-1""#).unwrap();
+        assert_eq!(escaped, expected);
+        let owned: PeripheralId = serde_json::from_value(serde_json::json!("abc-1")).unwrap();
+        assert_eq!(owned, expected);
+        let read: PeripheralId = serde_json::from_reader(&br#""abc-1""#[..]).unwrap();
+        assert_eq!(read, expected);
+        assert!(serde_json::from_value::<PeripheralId>(serde_json::json!(1)).is_err());
+    }
+
+    #[cfg(feature = "services_dynamodb")]
+    #[test]
+    fn dynamodb_attribute() {
+        use aws_sdk_dynamodb::model::AttributeValue;
+        use std::collections::HashMap;
+
+        #[derive(serde::Deserialize, serde::Serialize, Debug, PartialEq)]
+        struct Item {
+            #[serde(rename = "peripheralId")]
+            peripheral_id: PeripheralId,
+        }
+
+        let peripheral_id = PeripheralId::from_str("abc-1").unwrap();
+        let attr_value = AttributeValue::from(peripheral_id.clone());
+        assert_eq!(attr_value, AttributeValue::S("abc-1".to_string()));
+        assert_eq!(PeripheralId::try_from(&attr_value).unwrap(), peripheral_id);
+        assert!(PeripheralId::try_from(AttributeValue::N("1".to_string())).is_err());
+
+        let item = HashMap::from([("peripheralId".to_string(), attr_value)]);
+        let back: Item = serde_dynamo::from_item(item.clone()).unwrap();
+        assert_eq!(back.peripheral_id, peripheral_id);
+        let written: HashMap<String, AttributeValue> = serde_dynamo::to_item(&back).unwrap();
+        assert_eq!(written, item);
+    }
+
     #[test]
     fn is_peripheral_id() {
         let tester = |input, exp_uuid, exp_index| {